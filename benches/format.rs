@@ -0,0 +1,39 @@
+//! Compares `write_decimal` with the `Display` of `Decimal` it replaces in
+//! the CSV output. Run with `cargo bench --bench format`.
+
+use std::hint::black_box;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use rust_coding_test::output::write_decimal;
+use rust_decimal::Decimal;
+
+const ROUNDS: usize = 1_000_000;
+
+fn time(name: &str, mut f: impl FnMut(&mut Vec<u8>, Decimal)) -> Duration {
+    let amounts = [
+        Decimal::new(15, 1),
+        Decimal::new(100005, 0),
+        Decimal::new(-12345678, 4),
+        Decimal::new(1, 4),
+    ];
+    let mut buf = Vec::with_capacity(64);
+    let start = Instant::now();
+    for i in 0..ROUNDS {
+        buf.clear();
+        f(&mut buf, black_box(amounts[i % amounts.len()]));
+        black_box(&buf);
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<14} {:>8.1} ns/amount",
+        name,
+        elapsed.as_nanos() as f64 / ROUNDS as f64
+    );
+    elapsed
+}
+
+fn main() {
+    time("Display", |buf, d| write!(buf, "{}", d).unwrap());
+    time("write_decimal", write_decimal);
+}
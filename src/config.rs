@@ -0,0 +1,459 @@
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+pub use crate::ledger::DecimalContext;
+use crate::output::{AmountStyle, BoolFormat, OutputFormat, SortBy};
+use crate::{ClientIdType, ErrorKind, Result, TransactionIdType, COMMAND_TYPES, MAX_SCALE};
+
+pub const USAGE: &str =
+    "[--max-clients <n>] [--require-explicit-open] [--clamp-precision] [--lenient-sign] \
+                         [--lenient-amounts [--currency-symbol <symbol>]] \
+                         [--input-scale <n>] [--currency-scale <currency>=<n>]... \
+                         [--display-scale <n>] [--trim-zeros] [--negative-held] \
+                         [--held-report <file>] [--dump-history <file>] \
+                         [--format csv|json|table | --pretty] [--output <file>]... \
+                         [--sort-by client|total|available|held[:asc|:desc]] [--bool-format int|text] \
+                         [--only-active] [--filter-client <id>] \
+                         [--with-counts] [--summary-only] [--groups <file>] [--totals] \
+                         [--currency-label <code>] \
+                         [--input-format csv|jsonl|json] [--strict-csv] [--comment-char <c>] \
+                         [--max-history <n>] [--reuse-finalized-tx-ids] \
+                         [--incremental] [--fail-fast-on-lock] [--allow-deposits-when-locked] \
+                         [--reserve <amount>] [--verify] \
+                         [--allow-manual-holds] \
+                         [--dispute-policy reject|hold_available|hold_all | --partial-disputes] \
+                         [--max-disputes-per-tx <n>] \
+                         [--allow-adjustments [--adjust-locked]] \
+                         [--allow-types <type>,...] [--deny-types <type>,...] \
+                         [--withdrawal-fee-bps <n> [--fee-client <id>]] \
+                         [--expect <file>] [--quiet] [--summary-json <file>] [--explain <row>] \
+                         [--manifest <file>] \
+                         [--dispute-status <tx>] [--max-retries <n>] [--timeout <seconds>] \
+                         [--precision <digits>] \
+                         [--rounding half_even|half_up|half_down|down|up|floor|ceiling] \
+                         (filename|directory|pattern [--merge <filename>]... \
+                         | --listen <addr> [--snapshot-interval <seconds>] [--rate-limit <n>] \
+                         [--priority-clients <id>,...])";
+
+/// Client credited with the withdrawal fees unless `--fee-client` is given.
+pub const FEE_CLIENT: ClientIdType = ClientIdType::MAX;
+
+/// Engine settings collected from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub input: String,
+    /// Further CSV files merged with `input` by their `timestamp` column.
+    pub merge_inputs: Vec<String>,
+    /// Upper bound on the number of distinct clients, unlimited when `None`.
+    pub max_clients: Option<usize>,
+    /// Reject transactions for clients which were not opened with an `open` command.
+    pub require_explicit_open: bool,
+    /// Round amounts with more decimal places than allowed instead of rejecting them.
+    pub clamp_precision: bool,
+    /// Apply a deposit with a negative amount as a withdrawal and vice versa,
+    /// instead of rejecting it.
+    pub lenient_sign: bool,
+    /// Accept amounts written with a leading `+` or `currency_symbol`.
+    pub lenient_amounts: bool,
+    /// Symbol which may precede an amount under `lenient_amounts`, e.g. `$`.
+    pub currency_symbol: Option<String>,
+    /// Decimal places allowed for the amounts of currencies not listed in
+    /// `currency_scales` and of rows without a currency, four when `None`.
+    pub input_scale: Option<u32>,
+    /// Decimal places allowed for the amounts of a currency.
+    pub currency_scales: HashMap<String, u32>,
+    /// Decimal places the written amounts are rounded to, unrounded when `None`.
+    pub display_scale: Option<u32>,
+    /// Write the amounts without trailing zeros.
+    pub trim_zeros: bool,
+    /// Write `held` as a negative number.
+    pub negative_held: bool,
+    /// Where to write the funds held by each open dispute.
+    pub held_report: Option<String>,
+    /// Where to write every transaction of the history.
+    pub dump_history: Option<String>,
+    pub format: OutputFormat,
+    /// Files to write the balances to, `-` for the standard output.
+    /// The standard output alone when empty.
+    pub outputs: Vec<String>,
+    /// Order of the account summary, client id ascending by default.
+    pub sort_by: SortBy,
+    /// Rendering of the `locked` column of the CSV output.
+    pub bool_format: BoolFormat,
+    /// Limit the summary to clients which received a command in this run.
+    pub only_active: bool,
+    /// Process only the commands of this client, skipping all the others.
+    pub filter_client: Option<ClientIdType>,
+    /// Add the number of applied commands of every client to the output.
+    pub with_counts: bool,
+    /// Write the totals of every currency instead of the accounts.
+    pub summary_only: bool,
+    /// Group of every client, for a `group` column and the totals of every group.
+    pub groups: Option<String>,
+    /// Follow the accounts with a `TOTAL` row of every currency.
+    pub totals: bool,
+    /// Currency written for the accounts in the default currency, which
+    /// otherwise have none and are written without a currency column.
+    pub currency_label: Option<String>,
+    pub input_format: InputFormat,
+    /// Reject CSV rows whose number of fields differs from the header.
+    pub strict_csv: bool,
+    /// Skip the input lines starting with this character, e.g. `#`.
+    pub comment_char: Option<u8>,
+    /// Number of transactions kept for dispute lookups, unlimited when `None`.
+    pub max_history: Option<usize>,
+    /// Accept a deposit reusing the id of a charged back transaction,
+    /// which it replaces in the history.
+    pub reuse_finalized_tx_ids: bool,
+    /// Write the balance of the affected client after every applied
+    /// command instead of a summary at the end.
+    pub incremental: bool,
+    /// Silently skip commands of accounts locked by a chargeback.
+    pub fail_fast_on_lock: bool,
+    /// Accept deposits to accounts locked by a chargeback, everything
+    /// else stays rejected.
+    pub allow_deposits_when_locked: bool,
+    /// Minimum available amount a withdrawal has to leave on the account.
+    pub reserve: Decimal,
+    /// Accept `hold` and `release` commands moving funds between the
+    /// available and held amounts without a dispute.
+    pub allow_manual_holds: bool,
+    /// What to do with a dispute exceeding the available funds.
+    pub dispute_policy: DisputePolicy,
+    /// Number of times a transaction may be disputed, unlimited when `None`.
+    pub max_disputes_per_tx: Option<u32>,
+    /// Accept `adjust` commands adding a signed amount to the available funds.
+    pub allow_adjustments: bool,
+    /// Apply adjustments to locked accounts too.
+    pub adjust_locked: bool,
+    /// Command types to accept, all of them when `None`.
+    pub allow_types: Option<HashSet<String>>,
+    /// Command types to reject, even if listed in `allow_types`.
+    pub deny_types: HashSet<String>,
+    /// Fee charged on every withdrawal, in hundredths of a percent.
+    pub withdrawal_fee_bps: u32,
+    /// Client credited with the withdrawal fees, `FEE_CLIENT` when `None`.
+    pub fee_client: Option<ClientIdType>,
+    /// Cross-check the held funds against the open disputes after processing.
+    pub verify: bool,
+    /// Expected balances to compare the result with.
+    pub expect: Option<String>,
+    /// Do not report every rejected row, only their number at the end.
+    pub quiet: bool,
+    /// Where to write the counters of the run as a JSON object.
+    pub summary_json: Option<String>,
+    /// Where to write the inputs, counters and output hash of the run as
+    /// a JSON object.
+    pub manifest: Option<String>,
+    /// A row to trace against the state left by the input, without applying it.
+    pub explain: Option<String>,
+    /// A transaction whose dispute state to report after processing.
+    pub dispute_status: Option<TransactionIdType>,
+    /// Address to accept commands on instead of reading `input`.
+    pub listen: Option<String>,
+    /// Print the balances every that many seconds while listening.
+    pub snapshot_interval: Option<u64>,
+    /// Commands every client may send per second while listening,
+    /// unlimited when `None`.
+    pub rate_limit: Option<NonZeroU32>,
+    /// Clients whose queued commands are processed ahead of the others
+    /// while listening.
+    pub priority_clients: HashSet<ClientIdType>,
+    /// Times a read failing with a transient error is repeated in a row.
+    pub max_retries: u32,
+    /// Time after which processing stops, leaving the remaining input
+    /// unread, unlimited when `None`.
+    pub timeout: Option<Duration>,
+    /// Digits and rounding of the balance arithmetic.
+    pub decimal_context: DecimalContext,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    /// one JSON object per line, e.g. `{"type":"deposit","client":1,"tx":1,"amount":"1000"}`
+    Jsonl,
+}
+
+impl FromStr for InputFormat {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" | "json" => Ok(InputFormat::Jsonl),
+            _ => Err(ErrorKind::InvalidArgument.into()),
+        }
+    }
+}
+
+/// Handling of a dispute of a deposit whose funds were partly withdrawn
+/// or are held by other disputes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DisputePolicy {
+    /// reject the dispute with `FundsInsufficientForGivenOperation`
+    #[default]
+    Reject,
+    /// hold what is left available, the rest of the deposit stays undisputed
+    HoldAvailable,
+    /// hold the whole amount, leaving the shortfall as negative available funds
+    HoldAll,
+}
+
+impl FromStr for DisputePolicy {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(DisputePolicy::Reject),
+            "hold_available" => Ok(DisputePolicy::HoldAvailable),
+            "hold_all" => Ok(DisputePolicy::HoldAll),
+            _ => Err(ErrorKind::InvalidArgument.into()),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut config = Config::default();
+        let mut input = None;
+
+        let mut it = args.iter().skip(1);
+        while let Some(arg) = it.next() {
+            match arg.as_str() {
+                "--max-clients" => config.max_clients = Some(value(&mut it)?),
+                "--require-explicit-open" => config.require_explicit_open = true,
+                "--clamp-precision" => config.clamp_precision = true,
+                "--lenient-sign" => config.lenient_sign = true,
+                "--lenient-amounts" => config.lenient_amounts = true,
+                "--currency-symbol" => config.currency_symbol = Some(value(&mut it)?),
+                "--input-scale" => config.input_scale = Some(scale(value(&mut it)?)?),
+                "--display-scale" => config.display_scale = Some(scale(value(&mut it)?)?),
+                "--trim-zeros" => config.trim_zeros = true,
+                "--negative-held" => config.negative_held = true,
+                "--currency-scale" => {
+                    let (currency, scale) = currency_scale(&value::<String>(&mut it)?)?;
+                    config.currency_scales.insert(currency, scale);
+                }
+                "--held-report" => config.held_report = Some(value(&mut it)?),
+                "--dump-history" => config.dump_history = Some(value(&mut it)?),
+                "--format" => config.format = value(&mut it)?,
+                "--pretty" => config.format = OutputFormat::Table,
+                "--output" => config.outputs.push(value(&mut it)?),
+                "--sort-by" => config.sort_by = value(&mut it)?,
+                "--bool-format" => config.bool_format = value(&mut it)?,
+                "--only-active" => config.only_active = true,
+                "--filter-client" => config.filter_client = Some(value(&mut it)?),
+                "--with-counts" => config.with_counts = true,
+                "--summary-only" => config.summary_only = true,
+                "--groups" => config.groups = Some(value(&mut it)?),
+                "--totals" => config.totals = true,
+                "--currency-label" => config.currency_label = Some(value(&mut it)?),
+                "--input-format" => config.input_format = value(&mut it)?,
+                "--strict-csv" | "--strict-columns" => config.strict_csv = true,
+                "--comment-char" => {
+                    config.comment_char = Some(comment_char(&value::<String>(&mut it)?)?)
+                }
+                "--max-history" => config.max_history = Some(value(&mut it)?),
+                "--reuse-finalized-tx-ids" => config.reuse_finalized_tx_ids = true,
+                "--incremental" => config.incremental = true,
+                "--fail-fast-on-lock" => config.fail_fast_on_lock = true,
+                "--allow-deposits-when-locked" => config.allow_deposits_when_locked = true,
+                "--reserve" => config.reserve = value(&mut it)?,
+                "--verify" => config.verify = true,
+                "--allow-manual-holds" => config.allow_manual_holds = true,
+                "--dispute-policy" => config.dispute_policy = value(&mut it)?,
+                // deprecated alias of `--dispute-policy hold_available`
+                "--partial-disputes" => config.dispute_policy = DisputePolicy::HoldAvailable,
+                "--max-disputes-per-tx" => config.max_disputes_per_tx = Some(value(&mut it)?),
+                "--allow-adjustments" => config.allow_adjustments = true,
+                "--adjust-locked" => config.adjust_locked = true,
+                "--allow-types" => {
+                    config.allow_types = Some(command_types(&value::<String>(&mut it)?)?)
+                }
+                "--deny-types" => config.deny_types = command_types(&value::<String>(&mut it)?)?,
+                "--withdrawal-fee-bps" => config.withdrawal_fee_bps = value(&mut it)?,
+                "--fee-client" => config.fee_client = Some(value(&mut it)?),
+                "--expect" => config.expect = Some(value(&mut it)?),
+                "--quiet" => config.quiet = true,
+                "--summary-json" => config.summary_json = Some(value(&mut it)?),
+                "--manifest" => config.manifest = Some(value(&mut it)?),
+                "--explain" => config.explain = Some(value(&mut it)?),
+                "--dispute-status" => config.dispute_status = Some(value(&mut it)?),
+                "--merge" => config.merge_inputs.push(value(&mut it)?),
+                "--listen" => config.listen = Some(value(&mut it)?),
+                "--snapshot-interval" => config.snapshot_interval = Some(value(&mut it)?),
+                "--rate-limit" => config.rate_limit = Some(value(&mut it)?),
+                "--priority-clients" => {
+                    config.priority_clients = client_ids(&value::<String>(&mut it)?)?
+                }
+                "--max-retries" => config.max_retries = value(&mut it)?,
+                "--timeout" => config.timeout = Some(Duration::from_secs(value(&mut it)?)),
+                "--precision" => {
+                    config.decimal_context.precision = Some(precision(value(&mut it)?)?)
+                }
+                "--rounding" => {
+                    config.decimal_context.rounding = rounding(&value::<String>(&mut it)?)?
+                }
+                flag if flag.starts_with("--") => bail!(ErrorKind::InvalidArgument),
+                path => {
+                    if input.replace(path.to_string()).is_some() {
+                        bail!(ErrorKind::InvalidArgument)
+                    }
+                }
+            }
+        }
+        match (&config.listen, input) {
+            // a file is processed as fast as it is read
+            (None, Some(_)) if config.rate_limit.is_some() => bail!(ErrorKind::InvalidArgument),
+            (None, Some(_)) if !config.priority_clients.is_empty() => {
+                bail!(ErrorKind::InvalidArgument)
+            }
+            (None, Some(input)) => config.input = input,
+            (Some(_), None) => {}
+            _ => bail!(ErrorKind::InvalidArgument),
+        }
+        if (config.summary_only || config.groups.is_some())
+            && (config.incremental || config.format == OutputFormat::Table)
+        {
+            bail!(ErrorKind::InvalidArgument)
+        }
+        // the accounts and the group totals would be two JSON documents
+        if config.groups.is_some() && !config.summary_only && config.format == OutputFormat::Json {
+            bail!(ErrorKind::InvalidArgument)
+        }
+        // the footer is a CSV row below the accounts
+        if config.totals
+            && (config.summary_only || config.incremental || config.format != OutputFormat::Csv)
+        {
+            bail!(ErrorKind::InvalidArgument)
+        }
+        // the symbol is only stripped from lenient amounts
+        if config.currency_symbol.is_some() && !config.lenient_amounts {
+            bail!(ErrorKind::InvalidArgument)
+        }
+        // JSON has booleans of its own, the table is for people
+        if config.bool_format == BoolFormat::Int && config.format != OutputFormat::Csv {
+            bail!(ErrorKind::InvalidArgument)
+        }
+        if !config.merge_inputs.is_empty()
+            && (config.listen.is_some() || config.input_format != InputFormat::Csv)
+        {
+            bail!(ErrorKind::InvalidArgument)
+        }
+        Ok(config)
+    }
+
+    pub fn amount_style(&self) -> AmountStyle {
+        AmountStyle {
+            scale: self.display_scale,
+            trim_zeros: self.trim_zeros,
+            negative_held: self.negative_held,
+        }
+    }
+
+    /// Whether commands of type `type_` may be processed.
+    pub fn allows_type(&self, type_: &str) -> bool {
+        self.allow_types.as_ref().is_none_or(|t| t.contains(type_))
+            && !self.deny_types.contains(type_)
+    }
+
+    pub fn fee_client(&self) -> ClientIdType {
+        self.fee_client.unwrap_or(FEE_CLIENT)
+    }
+
+    /// Decimal places allowed for amounts of `currency`.
+    pub fn max_scale(&self, currency: Option<&str>) -> u32 {
+        currency
+            .and_then(|c| self.currency_scales.get(c))
+            .copied()
+            .unwrap_or(self.input_scale.unwrap_or(MAX_SCALE))
+    }
+}
+
+/// Parses `<currency>=<n>`.
+fn currency_scale(s: &str) -> Result<(String, u32)> {
+    match s.split_once('=') {
+        Some((currency, n)) if !currency.is_empty() => match n.parse() {
+            Ok(n) => Ok((currency.to_string(), scale(n)?)),
+            _ => bail!(ErrorKind::InvalidArgument),
+        },
+        _ => bail!(ErrorKind::InvalidArgument),
+    }
+}
+
+/// Parses a comma separated list of command types.
+fn command_types(s: &str) -> Result<HashSet<String>> {
+    s.split(',')
+        .map(str::trim)
+        .map(|t| {
+            if COMMAND_TYPES.contains(&t) {
+                Ok(t.to_string())
+            } else {
+                bail!(ErrorKind::InvalidArgument)
+            }
+        })
+        .collect()
+}
+
+/// Parses a comma separated list of client ids.
+fn client_ids(s: &str) -> Result<HashSet<ClientIdType>> {
+    s.split(',')
+        .map(|id| {
+            id.trim()
+                .parse()
+                .map_err(|_| ErrorKind::InvalidArgument.into())
+        })
+        .collect()
+}
+
+/// Checks a number of decimal places against the most `Decimal` can represent.
+fn scale(n: u32) -> Result<u32> {
+    if n <= 28 {
+        Ok(n)
+    } else {
+        bail!(ErrorKind::InvalidArgument)
+    }
+}
+
+/// Parses a single ASCII character, which is what the CSV reader compares bytes with.
+fn comment_char(s: &str) -> Result<u8> {
+    match s.as_bytes() {
+        [c] if c.is_ascii() => Ok(*c),
+        _ => bail!(ErrorKind::InvalidArgument),
+    }
+}
+
+/// Checks a number of significant digits against what `Decimal` can represent.
+fn precision(n: u32) -> Result<u32> {
+    if (1..=28).contains(&n) {
+        Ok(n)
+    } else {
+        bail!(ErrorKind::InvalidArgument)
+    }
+}
+
+/// Parses the name of a rounding strategy, `half_even` being banker's rounding.
+fn rounding(s: &str) -> Result<RoundingStrategy> {
+    match s {
+        "half_even" => Ok(RoundingStrategy::MidpointNearestEven),
+        "half_up" => Ok(RoundingStrategy::MidpointAwayFromZero),
+        "half_down" => Ok(RoundingStrategy::MidpointTowardZero),
+        "down" => Ok(RoundingStrategy::ToZero),
+        "up" => Ok(RoundingStrategy::AwayFromZero),
+        "floor" => Ok(RoundingStrategy::ToNegativeInfinity),
+        "ceiling" => Ok(RoundingStrategy::ToPositiveInfinity),
+        _ => bail!(ErrorKind::InvalidArgument),
+    }
+}
+
+fn value<'a, T: FromStr>(it: &mut impl Iterator<Item = &'a String>) -> Result<T> {
+    it.next()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| ErrorKind::InvalidArgument.into())
+}
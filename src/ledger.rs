@@ -0,0 +1,337 @@
+//! Balance arithmetic of the engine.
+//!
+//! Nothing here uses the async runtime, the CSV reader, std collections or
+//! the error types of the engine, only `core` and `rust_decimal`, so that
+//! the ledger math can be reused on its own, e.g. in a `no_std` + `alloc`
+//! build. `tests/ledger.rs` compiles this file outside of the library to
+//! keep it that way. The engine in the crate root wraps it.
+
+use core::fmt::{self, Formatter};
+use rust_decimal::{Decimal, RoundingStrategy};
+
+/// Why a balance operation or an amount was refused. The engine turns
+/// every variant into the `ErrorKind` of the same name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedgerError {
+    AmountNotPositive,
+    LockedBalance,
+    FundsInsufficientForGivenOperation,
+    AmountOverflow,
+    DecimalFormatError,
+    Decimal(rust_decimal::Error),
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerError::Decimal(e) => write!(f, "{}", e),
+            e => write!(f, "{:?}", e),
+        }
+    }
+}
+
+type Result<T> = core::result::Result<T, LedgerError>;
+
+pub(crate) const ZERO_AMOUNT: Decimal = Decimal::ZERO;
+
+/// The changes of a balance, each returning the new balance and leaving
+/// the old one as it is.
+pub trait BalanceOperation
+where
+    Self: Sized,
+{
+    fn deposit(&self, amount: Decimal) -> Result<Self>;
+    fn withdrawal(&self, amount: Decimal) -> Result<Self>;
+    fn dispute(&self, amount: Decimal) -> Result<Self>;
+    fn dispute_overdraft(&self, amount: Decimal) -> Result<Self>;
+    fn resolve(&self, amount: Decimal) -> Result<Self>;
+    fn chargeback(&self, amount: Decimal) -> Result<Self>;
+    fn hold(&self, amount: Decimal) -> Result<Self>;
+    fn release(&self, amount: Decimal) -> Result<Self>;
+    fn adjust(&self, amount: Decimal) -> Result<Self>;
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Balance {
+    pub(crate) avail: Decimal,
+    pub(crate) held: Decimal,
+    pub(crate) locked: bool,
+    /// the part of `held` placed by `hold` commands rather than disputes
+    pub(crate) manual_held: Decimal,
+}
+
+impl Balance {
+    pub fn new() -> Self {
+        Self {
+            avail: ZERO_AMOUNT,
+            held: ZERO_AMOUNT,
+            locked: false,
+            manual_held: ZERO_AMOUNT,
+        }
+    }
+
+    pub fn available(&self) -> Decimal {
+        self.avail
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Available and held funds together.
+    pub fn total(&self) -> Result<Decimal> {
+        checked_add(self.avail, self.held)
+    }
+
+    /// Adds to the available funds, whether the account is locked or not.
+    pub(crate) fn credit(&self, amount: Decimal) -> Result<Self> {
+        // the total reported for the account has to stay representable too
+        checked_add(self.total()?, amount)?;
+
+        Ok(Balance {
+            avail: checked_add(self.avail, amount)?,
+            ..*self
+        })
+    }
+
+    /// Applies `operation` as if the account were not locked, leaving it
+    /// as locked as it was.
+    pub(crate) fn settle(&self, operation: impl FnOnce(&Self) -> Result<Self>) -> Result<Self> {
+        let unlocked = Balance {
+            locked: false,
+            ..*self
+        };
+        let settled = operation(&unlocked)?;
+        Ok(Balance {
+            locked: self.locked || settled.locked,
+            ..settled
+        })
+    }
+
+    /// Rounds every amount to the precision of `context`.
+    pub(crate) fn rounded(&self, context: &DecimalContext) -> Result<Self> {
+        Ok(Balance {
+            avail: context.round(self.avail)?,
+            held: context.round(self.held)?,
+            manual_held: context.round(self.manual_held)?,
+            ..*self
+        })
+    }
+}
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.avail,
+            self.held,
+            self.total().map_err(|_| fmt::Error)?,
+            self.locked
+        )
+    }
+}
+
+pub(crate) fn bail_if_locked(balance: &Balance) -> Result<()> {
+    if balance.locked {
+        Err(LedgerError::LockedBalance)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn check_amount(amount: Decimal) -> Result<()> {
+    if amount <= ZERO_AMOUNT {
+        Err(LedgerError::AmountNotPositive)
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn checked_add(a: Decimal, b: Decimal) -> Result<Decimal> {
+    a.checked_add(b).ok_or(LedgerError::AmountOverflow)
+}
+
+pub(crate) fn checked_sub(a: Decimal, b: Decimal) -> Result<Decimal> {
+    a.checked_sub(b).ok_or(LedgerError::AmountOverflow)
+}
+
+impl BalanceOperation for Balance {
+    fn deposit(&self, amount: Decimal) -> Result<Self> {
+        bail_if_locked(self)?;
+        self.credit(amount)
+    }
+
+    fn withdrawal(&self, amount: Decimal) -> Result<Self> {
+        bail_if_locked(self)?;
+
+        if self.avail < amount {
+            Err(LedgerError::FundsInsufficientForGivenOperation)
+        } else {
+            // withdrawing everything leaves zero whatever the scales of the
+            // two amounts, not e.g. `0.0000`
+            let avail = checked_sub(self.avail, amount)?;
+            Ok(Balance {
+                avail: if avail.is_zero() { ZERO_AMOUNT } else { avail },
+                ..*self
+            })
+        }
+    }
+
+    fn dispute(&self, amount: Decimal) -> Result<Self> {
+        bail_if_locked(self)?;
+
+        if self.avail < amount {
+            Err(LedgerError::FundsInsufficientForGivenOperation)
+        } else {
+            Ok(Balance {
+                avail: checked_sub(self.avail, amount)?,
+                held: checked_add(self.held, amount)?,
+                ..*self
+            })
+        }
+    }
+
+    /// Holds the whole amount, even if that leaves the available funds negative.
+    fn dispute_overdraft(&self, amount: Decimal) -> Result<Self> {
+        bail_if_locked(self)?;
+
+        Ok(Balance {
+            avail: checked_sub(self.avail, amount)?,
+            held: checked_add(self.held, amount)?,
+            ..*self
+        })
+    }
+
+    fn resolve(&self, amount: Decimal) -> Result<Self> {
+        bail_if_locked(self)?;
+
+        if self.held < amount {
+            Err(LedgerError::FundsInsufficientForGivenOperation)
+        } else {
+            Ok(Balance {
+                avail: checked_add(self.avail, amount)?,
+                held: checked_sub(self.held, amount)?,
+                ..*self
+            })
+        }
+    }
+
+    fn chargeback(&self, amount: Decimal) -> Result<Self> {
+        bail_if_locked(self)?;
+
+        if self.held < amount {
+            Err(LedgerError::FundsInsufficientForGivenOperation)
+        } else {
+            Ok(Balance {
+                held: checked_sub(self.held, amount)?,
+                locked: true,
+                ..*self
+            })
+        }
+    }
+
+    fn hold(&self, amount: Decimal) -> Result<Self> {
+        bail_if_locked(self)?;
+
+        if self.avail < amount {
+            Err(LedgerError::FundsInsufficientForGivenOperation)
+        } else {
+            Ok(Balance {
+                avail: checked_sub(self.avail, amount)?,
+                held: checked_add(self.held, amount)?,
+                manual_held: checked_add(self.manual_held, amount)?,
+                ..*self
+            })
+        }
+    }
+
+    fn release(&self, amount: Decimal) -> Result<Self> {
+        bail_if_locked(self)?;
+
+        // funds held by disputes are only released by resolving them
+        if self.manual_held < amount {
+            Err(LedgerError::FundsInsufficientForGivenOperation)
+        } else {
+            Ok(Balance {
+                avail: checked_add(self.avail, amount)?,
+                held: checked_sub(self.held, amount)?,
+                manual_held: checked_sub(self.manual_held, amount)?,
+                ..*self
+            })
+        }
+    }
+
+    /// Adds a signed amount to the available funds, which may leave them
+    /// negative. Unlike the other operations, the lock is checked by the
+    /// caller, since `--adjust-locked` lifts it.
+    fn adjust(&self, amount: Decimal) -> Result<Self> {
+        self.credit(amount)
+    }
+}
+
+/// Arithmetic context of the balance operations.
+///
+/// `Decimal` computes with integers and an explicit scale, never with
+/// floating point, so the balances of a run are the same on every platform.
+/// The context bounds the digits they keep: every amount of a balance is
+/// rounded to `precision` significant digits after each operation, and
+/// amounts rounded to a number of decimal places, like withdrawal fees and
+/// amounts under `--clamp-precision`, use `rounding` as well.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecimalContext {
+    /// significant digits kept, all of them when `None`
+    pub precision: Option<u32>,
+    pub rounding: RoundingStrategy,
+}
+
+impl Default for DecimalContext {
+    fn default() -> Self {
+        Self {
+            precision: None,
+            // what `Decimal::round_dp` uses
+            rounding: RoundingStrategy::MidpointNearestEven,
+        }
+    }
+}
+
+impl DecimalContext {
+    /// Rounds `d` to `precision` significant digits. An amount with fewer
+    /// digits is left as it is, rather than padded with zeros.
+    pub fn round(&self, d: Decimal) -> Result<Decimal> {
+        match self.precision {
+            Some(digits) => {
+                let rounded = d
+                    .round_sf_with_strategy(digits, self.rounding)
+                    .ok_or(LedgerError::AmountOverflow)?;
+                Ok(if rounded.scale() > d.scale() {
+                    d
+                } else {
+                    rounded
+                })
+            }
+            None => Ok(d),
+        }
+    }
+
+    /// Rounds `d` to `scale` decimal places.
+    pub fn round_dp(&self, d: Decimal, scale: u32) -> Decimal {
+        d.round_dp_with_strategy(scale, self.rounding)
+    }
+}
+
+/// Parses an amount with at most `scale` decimal places. One with more is
+/// rounded under `clamp`, e.g. for `--clamp-precision`, and rejected otherwise.
+pub fn to_decimal(n: &str, scale: u32, clamp: Option<&DecimalContext>) -> Result<Decimal> {
+    let d = Decimal::from_str_radix(n, 10).map_err(LedgerError::Decimal)?;
+    if d.scale() <= scale {
+        Ok(d)
+    } else if let Some(context) = clamp {
+        Ok(context.round_dp(d, scale))
+    } else {
+        Err(LedgerError::DecimalFormatError)
+    }
+}
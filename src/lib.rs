@@ -0,0 +1,5980 @@
+// error_chain! expands recursively over every error kind
+#![recursion_limit = "256"]
+
+use core::fmt;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Formatter;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use csv_async::Trim;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+
+use serde_derive::{Deserialize, Serialize};
+
+use tokio::sync::mpsc;
+
+#[macro_use]
+extern crate error_chain;
+
+pub mod config;
+pub mod ledger;
+pub mod output;
+use config::{Config, DisputePolicy, InputFormat};
+use ledger::{bail_if_locked, check_amount, checked_add, checked_sub, LedgerError, ZERO_AMOUNT};
+pub use ledger::{Balance, BalanceOperation};
+use output::{AccountRecord, HistoryRecord, OutputSink};
+
+error_chain! {
+
+    errors{
+         AmountNotPositive{}
+         LockedBalance{}
+         FundsInsufficientForGivenOperation{}
+         InvalidArgument{}
+         UnknownTransationType{}
+         DecimalFormatError{}
+         TransactionAlreadyExist{}
+         TransactionAlreadyInDispute{}
+         ReferenceTransactionTypeIncorrect{}
+         ReferenceTransactionNotFound{}
+         ReferenceTransactionIncorrect{}
+         NotCurrentlyDisputed{
+             display("the referenced transaction is not in dispute")
+         }
+         ClientLimitExceeded{
+             display("more distinct clients than the client limit")
+         }
+         TransactionFinalized{}
+         AccountNotOpen{}
+         DisputeAmountExceeded{}
+         ChargebackAmountExceeded{}
+         ReserveBreach{}
+         AmountOverflow{}
+         SummaryOverflow{
+             display("the totals of the accounts exceed the range of amounts")
+         }
+         ManualHoldsNotAllowed{}
+         TransactionTypeNotAllowed{}
+         AdjustmentsNotAllowed{}
+         DisputeLimitExceeded{}
+         RateLimited{}
+         MissingField(field: &'static str) {
+             display("missing field '{}'", field)
+         }
+         UnexpectedField(field: &'static str) {
+             display("unexpected field '{}'", field)
+         }
+         ConsumerTerminated{
+             display("consumer terminated")
+         }
+         DuplicateHeader(column: String) {
+             display("duplicate column '{}'", column)
+         }
+         UnexpectedColumn(column: String) {
+             display("unexpected column '{}'", column)
+         }
+         ExpectationMismatch(count: usize) {
+             display("{} mismatches against the expected balances", count)
+         }
+         LedgerInvariantViolated(client: ClientIdType) {
+             display("held funds of client {} do not match its open disputes", client)
+         }
+         CompressedInput(format: &'static str) {
+             display("the input is {} compressed, decompress it first", format)
+         }
+         ReferenceTransactionFilteredOut(tx: TransactionIdType) {
+             display("transaction {} is of a client skipped by the client filter", tx)
+         }
+         NoInputMatched(pattern: String) {
+             display("no input matches {}", pattern)
+         }
+         CannotOpen(path: String, reason: String) {
+             display("cannot open '{}': {}", path, reason)
+         }
+         RunTimedOut(timeout: Duration) {
+             display("processing took longer than {:?}", timeout)
+         }
+         GroupConflict(client: ClientIdType) {
+             display("client {} is assigned to more than one group", client)
+         }
+         InternalInconsistency(type_: String) {
+             display("command type '{}' reached a step which does not handle it", type_)
+         }
+    }
+    foreign_links{
+        Io(::std::io::Error);
+        Decimal(::rust_decimal::Error);
+        CSV(csv_async::Error);
+    }
+}
+
+impl From<LedgerError> for Error {
+    fn from(e: LedgerError) -> Self {
+        match e {
+            LedgerError::AmountNotPositive => ErrorKind::AmountNotPositive.into(),
+            LedgerError::LockedBalance => ErrorKind::LockedBalance.into(),
+            LedgerError::FundsInsufficientForGivenOperation => {
+                ErrorKind::FundsInsufficientForGivenOperation.into()
+            }
+            LedgerError::AmountOverflow => ErrorKind::AmountOverflow.into(),
+            LedgerError::DecimalFormatError => ErrorKind::DecimalFormatError.into(),
+            LedgerError::Decimal(e) => e.into(),
+        }
+    }
+}
+
+/// Whether repeating the operation which failed may succeed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorClass {
+    /// e.g. a read interrupted by a signal or timing out on a slow socket
+    Transient,
+    /// everything else, from a malformed row to a closed connection
+    Fatal,
+}
+
+fn is_transient(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::Interrupted | std::io::ErrorKind::TimedOut
+    )
+}
+
+impl Error {
+    pub fn class(&self) -> ErrorClass {
+        let io = match self.kind() {
+            ErrorKind::Io(e) => Some(e),
+            // the CSV reader passes on the errors of the reader below it
+            ErrorKind::CSV(e) => match e.kind() {
+                csv_async::ErrorKind::Io(e) => Some(e),
+                _ => None,
+            },
+            _ => None,
+        };
+        match io {
+            Some(e) if is_transient(e) => ErrorClass::Transient,
+            _ => ErrorClass::Fatal,
+        }
+    }
+}
+
+pub type CommandType = String;
+pub type ClientIdType = u16;
+pub type TransactionIdType = u32;
+/// Currency of an account, `None` for rows without a currency.
+pub type Currency = Option<String>;
+
+/// A row as it appears in the input, before the fields required by its type are checked.
+#[derive(Debug, Deserialize)]
+struct CommandRecord {
+    #[serde(rename = "type")]
+    type_: String,
+    client: Option<ClientIdType>,
+    tx: Option<TransactionIdType>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    amount: Option<String>,
+    currency: Option<String>,
+}
+
+/// Reads an empty or blank string as an absent value, as the CSV reader
+/// already does for empty fields, e.g. for `{"amount": ""}`.
+fn empty_as_none<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(value.filter(|v| !v.trim().is_empty()))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "CommandRecord")]
+pub struct Command {
+    pub type_: String,
+    pub client_id: ClientIdType,
+    pub tx_id: TransactionIdType,
+    pub amount: Option<String>,
+    pub currency: Currency,
+}
+
+impl TryFrom<CommandRecord> for Command {
+    type Error = Error;
+
+    fn try_from(record: CommandRecord) -> Result<Self> {
+        let client_id = record.client.ok_or(ErrorKind::MissingField("client"))?;
+        let tx_id = record.tx.ok_or(ErrorKind::MissingField("tx"))?;
+        match (record.type_.as_str(), &record.amount) {
+            (DEPOSIT | WITHDRAWAL | HOLD | RELEASE | ADJUST, None) => {
+                bail!(ErrorKind::MissingField("amount"))
+            }
+            (OPEN, Some(_)) => bail!(ErrorKind::UnexpectedField("amount")),
+            (
+                DEPOSIT | WITHDRAWAL | DISPUTE | RESOLVE | CHARGEBACK | OPEN | HOLD | RELEASE
+                | ADJUST,
+                _,
+            ) => {}
+            _ => bail!(ErrorKind::UnknownTransationType),
+        }
+        Ok(Command {
+            type_: record.type_,
+            client_id,
+            tx_id,
+            amount: record.amount,
+            currency: record.currency,
+        })
+    }
+}
+
+pub type AmountType = Option<Decimal>;
+
+/// The commands which are kept in the history.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+    Adjustment,
+}
+
+impl TransactionKind {
+    /// The type of the command which created the transaction.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionKind::Deposit => DEPOSIT,
+            TransactionKind::Withdrawal => WITHDRAWAL,
+            TransactionKind::Adjustment => ADJUST,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Transaction {
+    kind: TransactionKind,
+    client_id: ClientIdType,
+    currency: Currency,
+    amount: AmountType,
+    pub in_dispute: bool,
+    /// the part of `amount` currently held by an open dispute
+    pub held: Decimal,
+    /// set once the transaction has been charged back, no further
+    /// dispute operations may reference it
+    pub finalized: bool,
+    /// set once part of what the dispute holds has been charged back, the
+    /// rest may still be resolved or charged back on the locked account
+    pub partly_charged_back: bool,
+    /// number of times the transaction has been disputed
+    pub disputes: u32,
+}
+pub type TransactionHistoryType = Arc<RwLock<TransactionHistory>>;
+#[derive(Clone, Default)]
+pub struct TransactionHistory {
+    transactions: HashMap<TransactionIdType, Transaction>,
+    /// transaction ids in insertion order, oldest first
+    order: VecDeque<TransactionIdType>,
+}
+impl TransactionHistory {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> TransactionHistoryType {
+        Arc::new(RwLock::new(TransactionHistory::default()))
+    }
+
+    pub fn get(&self, tx_id: &TransactionIdType) -> Option<&Transaction> {
+        self.transactions.get(tx_id)
+    }
+
+    pub fn get_mut(&mut self, tx_id: &TransactionIdType) -> Option<&mut Transaction> {
+        self.transactions.get_mut(tx_id)
+    }
+
+    /// Looks the transaction up on behalf of `client_id`, transactions of
+    /// other clients are not found.
+    pub fn get_for(
+        &self,
+        client_id: ClientIdType,
+        tx_id: &TransactionIdType,
+    ) -> Option<&Transaction> {
+        self.get(tx_id).filter(|tx| tx.client_id == client_id)
+    }
+
+    pub fn get_mut_for(
+        &mut self,
+        client_id: ClientIdType,
+        tx_id: &TransactionIdType,
+    ) -> Option<&mut Transaction> {
+        self.get_mut(tx_id).filter(|tx| tx.client_id == client_id)
+    }
+
+    pub fn contains_key(&self, tx_id: &TransactionIdType) -> bool {
+        self.transactions.contains_key(tx_id)
+    }
+
+    /// Whether the transaction is currently in dispute, `None` for a
+    /// transaction which is not in the history.
+    pub fn is_in_dispute(&self, tx_id: &TransactionIdType) -> Option<bool> {
+        self.get(tx_id).map(|tx| tx.in_dispute)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&TransactionIdType, &Transaction)> {
+        self.transactions.iter()
+    }
+
+    /// Adds the transaction as the newest one, replacing any transaction
+    /// with the same id.
+    pub fn insert(&mut self, tx_id: TransactionIdType, tx: Transaction) {
+        if self.transactions.insert(tx_id, tx).is_some() {
+            self.order.retain(|id| *id != tx_id);
+        }
+        self.order.push_back(tx_id);
+    }
+
+    pub fn clear(&mut self) {
+        self.transactions.clear();
+        self.order.clear();
+    }
+
+    /// Gives the memory of removed transactions back to the allocator.
+    pub fn shrink_to_fit(&mut self) {
+        self.transactions.shrink_to_fit();
+        self.order.shrink_to_fit();
+    }
+
+    /// Drops the oldest transactions until at most `max_len` remain.
+    /// Transactions in dispute are never dropped, so the history may stay
+    /// above `max_len` while they are open. A dropped transaction can no
+    /// longer be disputed and its id is no longer checked for duplicates.
+    pub fn evict(&mut self, max_len: usize) {
+        while self.transactions.len() > max_len {
+            let Some(position) = self
+                .order
+                .iter()
+                .position(|tx_id| !self.transactions[tx_id].in_dispute)
+            else {
+                break;
+            };
+            if let Some(tx_id) = self.order.remove(position) {
+                self.transactions.remove(&tx_id);
+            }
+        }
+    }
+}
+
+const DEPOSIT: &str = "deposit";
+const WITHDRAWAL: &str = "withdrawal";
+const DISPUTE: &str = "dispute";
+const RESOLVE: &str = "resolve";
+const CHARGEBACK: &str = "chargeback";
+const OPEN: &str = "open";
+const HOLD: &str = "hold";
+const RELEASE: &str = "release";
+const ADJUST: &str = "adjust";
+
+/// Every command type the engine knows.
+const COMMAND_TYPES: [&str; 9] = [
+    DEPOSIT, WITHDRAWAL, DISPUTE, RESOLVE, CHARGEBACK, OPEN, HOLD, RELEASE, ADJUST,
+];
+
+const MAX_SCALE: u32 = 4;
+
+/// Under `--lenient-sign`, turns a deposit with a negative amount into a
+/// withdrawal of the absolute amount and vice versa. The type it turns
+/// into has to be allowed too, as if it had been written in the row.
+fn flip_sign(cmd: &Command, config: &Config) -> Result<Option<Command>> {
+    if !config.lenient_sign {
+        return Ok(None);
+    }
+    let type_ = match cmd.type_.as_str() {
+        DEPOSIT => WITHDRAWAL,
+        WITHDRAWAL => DEPOSIT,
+        _ => return Ok(None),
+    };
+    let Some(amount) = cmd.amount.as_deref().and_then(|a| a.strip_prefix('-')) else {
+        return Ok(None);
+    };
+    if !Decimal::from_str_radix(amount, 10).is_ok_and(|d| d > ZERO_AMOUNT) {
+        return Ok(None);
+    }
+    if !config.allows_type(type_) {
+        bail!(ErrorKind::TransactionTypeNotAllowed)
+    }
+    eprintln!(
+        "warning: {} {} of tx {} applied as {} {}",
+        cmd.type_,
+        cmd.amount.as_deref().unwrap_or_default(),
+        cmd.tx_id,
+        type_,
+        amount
+    );
+    Ok(Some(Command {
+        type_: type_.to_string(),
+        amount: Some(amount.to_string()),
+        currency: cmd.currency.clone(),
+        ..*cmd
+    }))
+}
+
+/// Under `--lenient-amounts`, drops a leading `+` and then the configured
+/// currency symbol, e.g. of `+$100.00`. A negative amount keeps its `-`
+/// after the symbol, as in `$-30`. Anything else left in front of the
+/// number, as in `$$100` or `+-100`, is rejected.
+fn strip_amount<'a>(n: &'a str, config: &Config) -> Result<&'a str> {
+    if !config.lenient_amounts {
+        return Ok(n);
+    }
+    let (plus, n) = match n.strip_prefix('+') {
+        Some(n) => (true, n),
+        None => (false, n),
+    };
+    let n = match &config.currency_symbol {
+        Some(symbol) => n.strip_prefix(symbol.as_str()).unwrap_or(n),
+        None => n,
+    };
+    let digits = match n.strip_prefix('-') {
+        Some(digits) if !plus => digits,
+        _ => n,
+    };
+    if digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        Ok(n)
+    } else {
+        Err(ErrorKind::DecimalFormatError.into())
+    }
+}
+
+fn to_decimal(n: &str, scale: u32, config: &Config) -> Result<Decimal> {
+    let clamp = config.clamp_precision.then_some(&config.decimal_context);
+    Ok(ledger::to_decimal(strip_amount(n, config)?, scale, clamp)?)
+}
+
+/// Whether `to_decimal` has to round the amount under `--clamp-precision`.
+fn is_over_precise(n: &str, scale: u32, config: &Config) -> bool {
+    strip_amount(n, config)
+        .and_then(|n| Ok(Decimal::from_str_radix(n, 10)?))
+        .is_ok_and(|d| d.scale() > scale)
+}
+
+/// The fee charged on top of a withdrawal of `amount`, rounded to `scale`.
+/// The fee account itself withdraws for free.
+fn withdrawal_fee(
+    amount: Decimal,
+    scale: u32,
+    client_id: ClientIdType,
+    config: &Config,
+) -> Result<Decimal> {
+    if config.withdrawal_fee_bps == 0 || client_id == config.fee_client() {
+        return Ok(ZERO_AMOUNT);
+    }
+    let fee = amount
+        .checked_mul(Decimal::from(config.withdrawal_fee_bps))
+        .and_then(|fee| fee.checked_div(Decimal::from(10_000)))
+        .ok_or(ErrorKind::AmountOverflow)?;
+    Ok(config.decimal_context.round_dp(fee, scale))
+}
+
+pub type BalancesType = Arc<RwLock<Balances>>;
+/// Balances of every client, one for each currency the client used.
+#[derive(Clone, Default)]
+pub struct Balances {
+    accounts: HashMap<ClientIdType, BTreeMap<Currency, Balance>>,
+}
+impl Balances {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> BalancesType {
+        Arc::new(RwLock::new(Balances::default()))
+    }
+
+    /// Balance of the client in the default currency.
+    pub fn get(&self, client_id: &ClientIdType) -> Option<&Balance> {
+        self.get_in(client_id, None)
+    }
+
+    pub fn get_in(&self, client_id: &ClientIdType, currency: Option<&str>) -> Option<&Balance> {
+        self.accounts
+            .get(client_id)?
+            .iter()
+            .find(|(c, _)| c.as_deref() == currency)
+            .map(|(_, balance)| balance)
+    }
+
+    /// Whether the client has a balance in any currency.
+    pub fn contains_key(&self, client_id: &ClientIdType) -> bool {
+        self.accounts.contains_key(client_id)
+    }
+
+    /// Number of distinct clients.
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    /// Balance of the client in `currency`, created empty if missing.
+    pub fn get_or_insert(
+        &mut self,
+        client_id: ClientIdType,
+        currency: Option<&str>,
+    ) -> &mut Balance {
+        self.accounts
+            .entry(client_id)
+            .or_default()
+            .entry(currency.map(str::to_string))
+            .or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ClientIdType, Option<&str>, &Balance)> {
+        self.accounts.iter().flat_map(|(client_id, accounts)| {
+            accounts
+                .iter()
+                .map(|(currency, balance)| (*client_id, currency.as_deref(), balance))
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.accounts.clear();
+    }
+}
+
+/// Clears the balances and the transaction history in place, so that the
+/// same maps can be reused for the next, unrelated feed.
+pub async fn reset(balances: &BalancesType, transaction_history: &TransactionHistoryType) {
+    balances.write().await.clear();
+    transaction_history.write().await.clear();
+}
+
+/// Empties the transaction history and frees its memory, keeping the
+/// balances. As with `--max-history`, the cleared transactions can no
+/// longer be disputed, and the funds held by their open disputes stay held.
+pub async fn clear_history(transaction_history: &TransactionHistoryType) {
+    let mut history = transaction_history.write().await;
+    history.clear();
+    history.shrink_to_fit();
+}
+
+/// Unlocks every account of a client locked by a chargeback. With
+/// `release_held`, the funds still held by its open disputes and manual
+/// holds are returned to the available funds and these disputes are closed
+/// as if resolved; otherwise they stay held until resolved as usual.
+pub async fn unfreeze(
+    balances: &BalancesType,
+    transaction_history: &TransactionHistoryType,
+    client_id: ClientIdType,
+    release_held: bool,
+) -> Result<()> {
+    // in the order of `do_cmd`, balances first
+    let mut balances = balances.write().await;
+    let mut history = transaction_history.write().await;
+    let Some(accounts) = balances.accounts.get_mut(&client_id) else {
+        bail!(ErrorKind::AccountNotOpen)
+    };
+    // computed before anything is changed, so that an overflow leaves
+    // every account as it was
+    let mut unlocked = Vec::with_capacity(accounts.len());
+    for (currency, balance) in accounts.iter() {
+        let released = balance.locked && release_held;
+        let balance = if released {
+            Balance {
+                avail: checked_add(balance.avail, balance.held)?,
+                held: ZERO_AMOUNT,
+                locked: false,
+                manual_held: ZERO_AMOUNT,
+            }
+        } else {
+            Balance {
+                locked: false,
+                ..*balance
+            }
+        };
+        unlocked.push((currency.clone(), balance, released));
+    }
+    for (currency, balance, released) in unlocked {
+        if released {
+            for tx in history.transactions.values_mut() {
+                if tx.in_dispute && tx.client_id == client_id && tx.currency == currency {
+                    tx.in_dispute = false;
+                    tx.held = ZERO_AMOUNT;
+                }
+            }
+        }
+        accounts.insert(currency, balance);
+    }
+    Ok(())
+}
+
+fn check_client_limit(balances: &Balances, config: &Config) -> Result<()> {
+    match config.max_clients {
+        Some(max_clients) if balances.len() >= max_clients => {
+            bail!(ErrorKind::ClientLimitExceeded)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Error for a command type which passed the validation of `do_cmd` but is
+/// not handled by one of its later steps, a logic error rather than bad input.
+fn unexpected_type(cmd: &Command) -> Error {
+    ErrorKind::InternalInconsistency(cmd.type_.clone()).into()
+}
+
+/// The amount a command moves, `None` when it refers to an unknown transaction.
+async fn command_amount(
+    cmd: &Command,
+    scale: u32,
+    config: &Config,
+    transaction_history: &TransactionHistoryType,
+) -> Result<Option<Decimal>> {
+    let amount = match cmd.type_.as_str() {
+        DISPUTE => {
+            let disputable = transaction_history
+                .read()
+                .await
+                .get_for(cmd.client_id, &cmd.tx_id)
+                .and_then(|tx| tx.amount);
+            match (&cmd.amount, disputable) {
+                // partial dispute, only the given part of the transaction is held
+                (Some(q), Some(disputable)) => {
+                    let partial = to_decimal(q.as_str(), scale, config)?;
+                    if partial > disputable {
+                        bail!(ErrorKind::DisputeAmountExceeded)
+                    }
+                    Some(partial)
+                }
+                (_, disputable) => disputable,
+            }
+        }
+        RESOLVE | CHARGEBACK => {
+            let held = transaction_history
+                .read()
+                .await
+                .get_for(cmd.client_id, &cmd.tx_id)
+                .map(|tx| tx.held);
+            match (&cmd.amount, held) {
+                // a part of what the dispute holds, the rest stays disputed;
+                // checked against what it still holds rather than the deposit,
+                // so that nothing is credited twice
+                (Some(q), Some(held)) => {
+                    let partial = to_decimal(q.as_str(), scale, config)?;
+                    if partial > held {
+                        // chargebacks of a transaction add up to at most
+                        // what its dispute held
+                        if cmd.type_ == CHARGEBACK {
+                            bail!(ErrorKind::ChargebackAmountExceeded)
+                        }
+                        bail!(ErrorKind::FundsInsufficientForGivenOperation)
+                    }
+                    Some(partial)
+                }
+                (_, held) => held,
+            }
+        }
+        DEPOSIT | WITHDRAWAL | HOLD | RELEASE | ADJUST => match &cmd.amount {
+            Some(q) => Some(to_decimal(q.as_str(), scale, config)?),
+            None => None,
+        },
+        _ => return Err(unexpected_type(cmd)),
+    };
+    Ok(amount)
+}
+
+/// Updates the history with an applied command.
+fn record_transaction(
+    history: &mut TransactionHistory,
+    cmd: &Command,
+    amount: Decimal,
+    config: &Config,
+) -> Result<()> {
+    match cmd.type_.as_str() {
+        HOLD | RELEASE => {}
+        DISPUTE => {
+            if let Some(tx) = history.get_mut_for(cmd.client_id, &cmd.tx_id) {
+                tx.in_dispute = true;
+                tx.held = amount;
+                tx.partly_charged_back = false;
+                tx.disputes += 1;
+            }
+        }
+        // the dispute stays open until all it holds is resolved or charged back
+        RESOLVE => {
+            if let Some(tx) = history.get_mut_for(cmd.client_id, &cmd.tx_id) {
+                tx.held = checked_sub(tx.held, amount)?;
+                tx.in_dispute = tx.held > ZERO_AMOUNT;
+            }
+        }
+        CHARGEBACK => {
+            if let Some(tx) = history.get_mut_for(cmd.client_id, &cmd.tx_id) {
+                tx.held = checked_sub(tx.held, amount)?;
+                tx.in_dispute = tx.held > ZERO_AMOUNT;
+                tx.finalized = !tx.in_dispute;
+                tx.partly_charged_back = tx.in_dispute;
+            }
+        }
+        DEPOSIT | WITHDRAWAL | ADJUST => {
+            let kind = match cmd.type_.as_str() {
+                DEPOSIT => TransactionKind::Deposit,
+                WITHDRAWAL => TransactionKind::Withdrawal,
+                _ => TransactionKind::Adjustment,
+            };
+            history.insert(
+                cmd.tx_id,
+                Transaction {
+                    kind,
+                    client_id: cmd.client_id,
+                    currency: cmd.currency.clone(),
+                    amount: Some(amount),
+                    in_dispute: false,
+                    held: ZERO_AMOUNT,
+                    finalized: false,
+                    partly_charged_back: false,
+                    disputes: 0,
+                },
+            );
+            if let Some(max_history) = config.max_history {
+                history.evict(max_history);
+            }
+        }
+        _ => return Err(unexpected_type(cmd)),
+    }
+    Ok(())
+}
+
+async fn open_account(cmd: &Command, config: &Config, balances: &BalancesType) -> Result<Outcome> {
+    let mut p = balances.write().await;
+    if p.get_in(&cmd.client_id, cmd.currency.as_deref()).is_some() {
+        return Ok(Outcome::Ignored);
+    }
+    if !p.contains_key(&cmd.client_id) {
+        check_client_limit(&p, config)?;
+    }
+    let balance = *p.get_or_insert(cmd.client_id, cmd.currency.as_deref());
+    Ok(Outcome::Applied(Applied {
+        client_id: cmd.client_id,
+        type_: cmd.type_.clone(),
+        currency: cmd.currency.clone(),
+        delta: ZERO_AMOUNT,
+        balance,
+    }))
+}
+
+/// The effect of an applied command on the account it names.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Applied {
+    pub client_id: ClientIdType,
+    /// the type the command was applied as, which differs from the row
+    /// for an amount whose sign was flipped under `--lenient-sign`
+    pub type_: CommandType,
+    pub currency: Currency,
+    /// the amount moved by the command, without the withdrawal fee,
+    /// and zero for `open`
+    pub delta: Decimal,
+    /// the balance of the account after the command
+    pub balance: Balance,
+}
+
+/// What `do_cmd` did with a command it did not reject.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// the command changed the state of the engine
+    Applied(Applied),
+    /// the account is locked and `--fail-fast-on-lock` is set
+    Skipped,
+    /// the command had nothing to do, e.g. opening an existing account
+    Ignored,
+}
+
+pub async fn do_cmd(
+    cmd: &Command,
+    config: &Config,
+    transaction_history: &TransactionHistoryType,
+    balances: &BalancesType,
+) -> Result<Outcome> {
+    if !config.allows_type(&cmd.type_) {
+        bail!(ErrorKind::TransactionTypeNotAllowed)
+    }
+    let flipped = flip_sign(cmd, config)?;
+    let cmd = flipped.as_ref().unwrap_or(cmd);
+    let exempt = cmd.type_ == DEPOSIT && config.allow_deposits_when_locked;
+    if config.fail_fast_on_lock && !exempt {
+        let locked = balances
+            .read()
+            .await
+            .get_in(&cmd.client_id, cmd.currency.as_deref())
+            .is_some_and(|balance| balance.locked);
+        if locked {
+            return Ok(Outcome::Skipped);
+        }
+    }
+    if cmd.type_.as_str() == OPEN {
+        return open_account(cmd, config, balances).await;
+    }
+    // whether the command settles the rest of a partly charged back dispute
+    let mut settling = false;
+    // check the transaction logic first
+    {
+        let guard = transaction_history.read().await;
+
+        match cmd.type_.as_str() {
+            DEPOSIT | WITHDRAWAL => {
+                // a charged back transaction is over, its id may be recycled
+                let reusable = cmd.type_ == DEPOSIT
+                    && config.reuse_finalized_tx_ids
+                    && guard.get(&cmd.tx_id).is_some_and(|tx| tx.finalized);
+                if guard.contains_key(&cmd.tx_id) && !reusable {
+                    bail!(ErrorKind::TransactionAlreadyExist)
+                }
+            }
+            ADJUST => {
+                if !config.allow_adjustments {
+                    bail!(ErrorKind::AdjustmentsNotAllowed)
+                }
+                if guard.contains_key(&cmd.tx_id) {
+                    bail!(ErrorKind::TransactionAlreadyExist)
+                }
+            }
+            // manual holds do not refer to any transaction
+            HOLD | RELEASE => {
+                if !config.allow_manual_holds {
+                    bail!(ErrorKind::ManualHoldsNotAllowed)
+                }
+            }
+            DISPUTE => {
+                if let Some(tx) = guard.get_for(cmd.client_id, &cmd.tx_id) {
+                    if tx.kind != TransactionKind::Deposit {
+                        bail!(ErrorKind::ReferenceTransactionTypeIncorrect);
+                    }
+                    if tx.currency != cmd.currency {
+                        bail!(ErrorKind::ReferenceTransactionIncorrect);
+                    }
+                    if tx.finalized {
+                        bail!(ErrorKind::TransactionFinalized);
+                    }
+                    if tx.in_dispute {
+                        bail!(ErrorKind::TransactionAlreadyInDispute);
+                    }
+                    if config
+                        .max_disputes_per_tx
+                        .is_some_and(|max| tx.disputes >= max)
+                    {
+                        bail!(ErrorKind::DisputeLimitExceeded);
+                    }
+                } else {
+                    bail!(ErrorKind::ReferenceTransactionNotFound)
+                }
+            }
+
+            RESOLVE | CHARGEBACK => {
+                if let Some(tx) = guard.get_for(cmd.client_id, &cmd.tx_id) {
+                    if tx.currency != cmd.currency {
+                        bail!(ErrorKind::ReferenceTransactionIncorrect);
+                    }
+                    if tx.finalized {
+                        bail!(ErrorKind::TransactionFinalized);
+                    }
+                    // never disputed, or its dispute was already resolved
+                    if !tx.in_dispute {
+                        bail!(ErrorKind::NotCurrentlyDisputed);
+                    }
+                    settling = tx.partly_charged_back;
+                } else {
+                    return Err(ErrorKind::ReferenceTransactionNotFound.into());
+                }
+            }
+            _ => return Err(ErrorKind::UnknownTransationType.into()),
+        }
+    }
+    // a locked account is reported as such whatever the amount, as the
+    // balance operation would
+    if matches!(cmd.type_.as_str(), RESOLVE | CHARGEBACK) && !settling {
+        let locked = balances
+            .read()
+            .await
+            .get_in(&cmd.client_id, cmd.currency.as_deref())
+            .is_some_and(|balance| balance.locked);
+        if locked {
+            bail!(ErrorKind::LockedBalance)
+        }
+    }
+    // check if amount is available for an operation
+    let scale = config.max_scale(cmd.currency.as_deref());
+    if let Some(amount) = command_amount(cmd, scale, config, transaction_history).await? {
+        // execute balance change, an adjustment being the one signed amount
+        if cmd.type_ != ADJUST {
+            check_amount(amount)?;
+        }
+        let client_id = cmd.client_id;
+        let mut p = balances.write().await;
+        if !p.contains_key(&client_id) {
+            if config.require_explicit_open {
+                bail!(ErrorKind::AccountNotOpen)
+            }
+            check_client_limit(&p, config)?;
+        }
+        let currency = cmd.currency.as_deref();
+        let balance = *p.get_or_insert(client_id, currency);
+        // disputes are funded in the order they arrive; a later one which
+        // exceeds what is left available holds only that under `hold_available`
+        let amount = if cmd.type_ == DISPUTE
+            && config.dispute_policy == DisputePolicy::HoldAvailable
+            && amount > balance.avail
+            && balance.avail > ZERO_AMOUNT
+        {
+            balance.avail
+        } else {
+            amount
+        };
+        // the new balance of the fee account, applied together with the withdrawal
+        let mut fee_credit = None;
+        let new_balance = match cmd.type_.as_str() {
+            DEPOSIT if config.allow_deposits_when_locked => balance.credit(amount)?,
+            DEPOSIT => balance.deposit(amount)?,
+            WITHDRAWAL => {
+                let fee = withdrawal_fee(amount, scale, client_id, config)?;
+                let new_balance = balance.withdrawal(checked_add(amount, fee)?)?;
+                if new_balance.avail < config.reserve {
+                    bail!(ErrorKind::ReserveBreach)
+                }
+                if fee > ZERO_AMOUNT {
+                    // opened like the account of any other client, but
+                    // credited even when locked so that a chargeback of
+                    // the fee client does not block every withdrawal
+                    let fee_client = config.fee_client();
+                    if !p.contains_key(&fee_client) {
+                        if config.require_explicit_open {
+                            bail!(ErrorKind::AccountNotOpen)
+                        }
+                        check_client_limit(&p, config)?;
+                    }
+                    let fee_balance = p.get_in(&fee_client, currency).copied();
+                    fee_credit = Some(fee_balance.unwrap_or_default().credit(fee)?);
+                }
+                new_balance
+            }
+            DISPUTE if config.dispute_policy == DisputePolicy::HoldAll => {
+                balance.dispute_overdraft(amount)?
+            }
+            DISPUTE => balance.dispute(amount)?,
+            // the account was locked by this very dispute
+            RESOLVE if settling => balance.settle(|b| b.resolve(amount))?,
+            CHARGEBACK if settling => balance.settle(|b| b.chargeback(amount))?,
+            RESOLVE => balance.resolve(amount)?,
+            CHARGEBACK => balance.chargeback(amount)?,
+            HOLD => balance.hold(amount)?,
+            RELEASE => balance.release(amount)?,
+            ADJUST => {
+                if !config.adjust_locked {
+                    bail_if_locked(&balance)?;
+                }
+                balance.adjust(amount)?
+            }
+            _ => return Err(unexpected_type(cmd)),
+        };
+        let new_balance = new_balance.rounded(&config.decimal_context)?;
+        *p.get_or_insert(client_id, currency) = new_balance;
+        if let Some(fee_balance) = fee_credit {
+            *p.get_or_insert(config.fee_client(), currency) =
+                fee_balance.rounded(&config.decimal_context)?;
+        }
+
+        // a dispute records what the rounded balance actually holds, so
+        // that the held funds keep matching the history under `--precision`
+        let recorded = match cmd.type_.as_str() {
+            DISPUTE => checked_sub(new_balance.held, balance.held)?,
+            RESOLVE | CHARGEBACK => checked_sub(balance.held, new_balance.held)?,
+            _ => amount,
+        };
+        // insert into or update the history
+        record_transaction(
+            &mut *transaction_history.write().await,
+            cmd,
+            recorded,
+            config,
+        )?;
+        Ok(Outcome::Applied(Applied {
+            client_id,
+            type_: cmd.type_.clone(),
+            currency: cmd.currency.clone(),
+            delta: amount,
+            balance: new_balance,
+        }))
+    } else {
+        Err(ErrorKind::UnknownTransationType.into())
+    }
+}
+
+/// Parses a single row in the column order `type, client, tx, amount, currency`.
+pub async fn parse_row(row: &str) -> Result<Command> {
+    use tokio_stream::StreamExt;
+
+    let data = format!("type,client,tx,amount,currency\n{}", row);
+    let mut rdr = csv_async::AsyncReaderBuilder::new()
+        .flexible(true)
+        .trim(Trim::All)
+        .create_deserializer(data.as_bytes());
+    let cmd = rdr.deserialize::<Command>().next().await;
+    match cmd {
+        Some(cmd) => Ok(cmd?),
+        None => bail!(ErrorKind::MissingField("type")),
+    }
+}
+
+fn describe(balance: &Balance) -> String {
+    format!(
+        "available {}, held {}, locked {}",
+        balance.avail, balance.held, balance.locked
+    )
+}
+
+/// Narrates what `do_cmd` would do with `cmd` in the current state: the
+/// account and the referenced transaction it looks at, and the resulting
+/// balance or the error. The state itself is left unchanged.
+pub async fn explain(
+    cmd: &Command,
+    config: &Config,
+    transaction_history: &TransactionHistoryType,
+    balances: &BalancesType,
+) -> Vec<String> {
+    let mut trace = vec![format!(
+        "{} of client {}, transaction {}{}{}",
+        cmd.type_,
+        cmd.client_id,
+        cmd.tx_id,
+        cmd.amount
+            .as_deref()
+            .map(|amount| format!(", amount {}", amount))
+            .unwrap_or_default(),
+        cmd.currency
+            .as_deref()
+            .map(|currency| format!(", currency {}", currency))
+            .unwrap_or_default(),
+    )];
+
+    let balances = Arc::new(RwLock::new(balances.read().await.clone()));
+    let transaction_history = Arc::new(RwLock::new(transaction_history.read().await.clone()));
+    match balances
+        .read()
+        .await
+        .get_in(&cmd.client_id, cmd.currency.as_deref())
+    {
+        Some(balance) => trace.push(format!("account: {}", describe(balance))),
+        None => trace.push("account: does not exist yet".to_string()),
+    }
+    {
+        let history = transaction_history.read().await;
+        match cmd.type_.as_str() {
+            DEPOSIT | WITHDRAWAL | ADJUST => match history.get(&cmd.tx_id) {
+                Some(_) => trace.push(format!("transaction {} is already used", cmd.tx_id)),
+                None => trace.push(format!("transaction {} is not used yet", cmd.tx_id)),
+            },
+            DISPUTE | RESOLVE | CHARGEBACK => match history.get(&cmd.tx_id) {
+                Some(tx) if tx.client_id == cmd.client_id => trace.push(format!(
+                    "referenced transaction: {:?} of {}{}, in dispute {}, held {}, finalized {}",
+                    tx.kind,
+                    tx.amount.unwrap_or_default(),
+                    tx.currency
+                        .as_deref()
+                        .map(|currency| format!(" {}", currency))
+                        .unwrap_or_default(),
+                    tx.in_dispute,
+                    tx.held,
+                    tx.finalized
+                )),
+                Some(tx) => trace.push(format!(
+                    "referenced transaction: not found, transaction {} belongs to client {}",
+                    cmd.tx_id, tx.client_id
+                )),
+                None => trace.push(format!(
+                    "referenced transaction: not found, no transaction {} in the history",
+                    cmd.tx_id
+                )),
+            },
+            _ => {}
+        }
+    }
+
+    match do_cmd(cmd, config, &transaction_history, &balances).await {
+        Ok(Outcome::Applied(applied)) => {
+            trace.push(format!("applied as {} of {}", applied.type_, applied.delta));
+            trace.push(format!("account becomes: {}", describe(&applied.balance)));
+        }
+        Ok(Outcome::Skipped) => {
+            trace.push("skipped: the account is locked and --fail-fast-on-lock is set".to_string())
+        }
+        Ok(Outcome::Ignored) => trace.push("ignored: nothing to do".to_string()),
+        Err(e) => trace.push(format!("rejected: {}", e)),
+    }
+    trace
+}
+
+/// Lists the transactions which currently hold funds, ordered by transaction id.
+pub async fn held_breakdown(
+    transaction_history: &TransactionHistoryType,
+) -> Vec<(ClientIdType, TransactionIdType, Decimal)> {
+    let mut held: Vec<_> = transaction_history
+        .read()
+        .await
+        .iter()
+        .filter(|(_, tx)| tx.in_dispute)
+        .map(|(tx_id, tx)| (tx.client_id, *tx_id, tx.held))
+        .collect();
+    held.sort_by_key(|(_, tx_id, _)| *tx_id);
+    held
+}
+
+/// Lists every transaction of the history, ordered by transaction id.
+pub async fn history_records(transaction_history: &TransactionHistoryType) -> Vec<HistoryRecord> {
+    let mut records: Vec<_> = transaction_history
+        .read()
+        .await
+        .iter()
+        .map(|(tx_id, tx)| HistoryRecord {
+            tx: *tx_id,
+            client: tx.client_id,
+            type_: tx.kind.as_str(),
+            amount: tx.amount.unwrap_or_default(),
+            in_dispute: tx.in_dispute,
+            held: tx.held,
+            finalized: tx.finalized,
+            currency: tx.currency.clone(),
+        })
+        .collect();
+    records.sort_by_key(|record| record.tx);
+    records
+}
+
+/// Checks that the held funds of every account equal the sum of the open
+/// disputes of its transactions and of its manual holds.
+pub async fn verify_held_consistency(
+    balances: &BalancesType,
+    transaction_history: &TransactionHistoryType,
+) -> Result<()> {
+    // in the order of `do_cmd`, balances first
+    let balances = balances.read().await;
+    let guard = transaction_history.read().await;
+    let mut disputed: HashMap<(ClientIdType, Option<&str>), Decimal> = HashMap::new();
+    for (_, tx) in guard.iter().filter(|(_, tx)| tx.in_dispute) {
+        let sum = disputed
+            .entry((tx.client_id, tx.currency.as_deref()))
+            .or_default();
+        *sum = checked_add(*sum, tx.held)?;
+    }
+
+    for (client_id, currency, balance) in balances.iter() {
+        let held = disputed.remove(&(client_id, currency)).unwrap_or_default();
+        if checked_add(held, balance.manual_held)? != balance.held {
+            bail!(ErrorKind::LedgerInvariantViolated(client_id))
+        }
+    }
+    // open disputes of accounts which do not exist
+    if let Some(((client_id, _), _)) = disputed.into_iter().next() {
+        bail!(ErrorKind::LedgerInvariantViolated(client_id))
+    }
+    Ok(())
+}
+
+/// A locked account which still holds funds. A chargeback only releases the
+/// funds of the dispute it finalizes, while the other open disputes of the
+/// account keep theirs, and since a locked account accepts no further
+/// commands they stay held.
+#[derive(Debug, PartialEq)]
+pub struct LockedHeld {
+    pub client: ClientIdType,
+    pub currency: Currency,
+    pub held: Decimal,
+    /// the transactions in dispute, ordered by id
+    pub disputes: Vec<TransactionIdType>,
+}
+
+impl fmt::Display for LockedHeld {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "client {}", self.client)?;
+        if let Some(currency) = &self.currency {
+            write!(f, " {}", currency)?;
+        }
+        write!(f, " is locked with {} held by the disputes of", self.held)?;
+        for (i, tx_id) in self.disputes.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " " } else { ", " }, tx_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists the locked accounts with held funds, ordered by client id and
+/// currency, after checking that these funds are the ones of their open
+/// disputes and manual holds.
+pub async fn locked_held(
+    balances: &BalancesType,
+    transaction_history: &TransactionHistoryType,
+) -> Result<Vec<LockedHeld>> {
+    // in the order of `do_cmd`, balances first
+    let balances = balances.read().await;
+    let guard = transaction_history.read().await;
+    let mut locked = Vec::new();
+    for (client_id, currency, balance) in balances.iter() {
+        if !balance.locked || balance.held == ZERO_AMOUNT {
+            continue;
+        }
+        let mut held = balance.manual_held;
+        let mut disputes = Vec::new();
+        for (tx_id, tx) in guard.iter() {
+            if tx.in_dispute && tx.client_id == client_id && tx.currency.as_deref() == currency {
+                held = checked_add(held, tx.held)?;
+                disputes.push(*tx_id);
+            }
+        }
+        if held != balance.held {
+            bail!(ErrorKind::LedgerInvariantViolated(client_id))
+        }
+        disputes.sort_unstable();
+        locked.push(LockedHeld {
+            client: client_id,
+            currency: currency.map(str::to_string),
+            held: balance.held,
+            disputes,
+        });
+    }
+    locked.sort_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+    Ok(locked)
+}
+
+/// Snapshot of all balances, ordered by client id and currency.
+pub async fn account_records(balances: &BalancesType) -> Result<Vec<AccountRecord>> {
+    let mut records = balances
+        .read()
+        .await
+        .iter()
+        .map(|(client_id, currency, balance)| AccountRecord::new(client_id, currency, balance))
+        .collect::<Result<Vec<_>>>()?;
+    records.sort_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+    Ok(records)
+}
+
+/// A row of an expected-balances file, in the format of the CSV summary.
+#[derive(Debug, Deserialize)]
+struct ExpectedRecord {
+    client: ClientIdType,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    currency: Option<String>,
+}
+
+/// A difference between the expected and the computed balances. A whole
+/// account missing on either side is reported with the field `account`.
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub client: ClientIdType,
+    pub currency: Currency,
+    pub field: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "client {}", self.client)?;
+        if let Some(currency) = &self.currency {
+            write!(f, " {}", currency)?;
+        }
+        write!(
+            f,
+            ": {} expected {}, actual {}",
+            self.field, self.expected, self.actual
+        )
+    }
+}
+
+/// A row of a `--groups` file.
+#[derive(Debug, Deserialize)]
+struct GroupRecord {
+    client: ClientIdType,
+    group: String,
+}
+
+/// Reads the group of every client from `reader`, a CSV file with the
+/// columns `client` and `group`.
+pub async fn read_groups<R>(reader: R) -> Result<HashMap<ClientIdType, String>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    use tokio_stream::StreamExt;
+
+    let mut groups = HashMap::new();
+    let mut rdr = csv_async::AsyncReaderBuilder::new()
+        .trim(Trim::All)
+        .create_deserializer(reader);
+    let mut rows = rdr.deserialize::<GroupRecord>();
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        match groups.insert(row.client, row.group.clone()) {
+            Some(group) if group != row.group => bail!(ErrorKind::GroupConflict(row.client)),
+            _ => {}
+        }
+    }
+    Ok(groups)
+}
+
+/// Compares `records` with the expected balances read from `reader`, a CSV
+/// file with the columns of the summary.
+pub async fn reconcile<R>(reader: R, records: &[AccountRecord]) -> Result<Vec<Mismatch>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    use tokio_stream::StreamExt;
+
+    let mut expected = HashMap::new();
+    let mut rdr = csv_async::AsyncReaderBuilder::new()
+        .trim(Trim::All)
+        .create_deserializer(reader);
+    let mut rows = rdr.deserialize::<ExpectedRecord>();
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        expected.insert((row.client, row.currency.clone()), row);
+    }
+
+    let mut mismatches = Vec::new();
+    let mut mismatch = |client, currency: &Currency, field, expected: String, actual: String| {
+        mismatches.push(Mismatch {
+            client,
+            currency: currency.clone(),
+            field,
+            expected,
+            actual,
+        });
+    };
+    for record in records {
+        let key = (record.client, record.currency.clone());
+        let Some(row) = expected.remove(&key) else {
+            mismatch(
+                record.client,
+                &record.currency,
+                "account",
+                "missing".to_string(),
+                "present".to_string(),
+            );
+            continue;
+        };
+        let fields = [
+            ("available", row.available, record.available),
+            ("held", row.held, record.held),
+            ("total", row.total, record.total),
+        ];
+        for (field, expected, actual) in fields {
+            // the values are compared, so `1.50` matches `1.5`
+            if expected != actual {
+                mismatch(
+                    record.client,
+                    &record.currency,
+                    field,
+                    expected.to_string(),
+                    actual.to_string(),
+                );
+            }
+        }
+        if row.locked != record.locked {
+            mismatch(
+                record.client,
+                &record.currency,
+                "locked",
+                row.locked.to_string(),
+                record.locked.to_string(),
+            );
+        }
+    }
+    let mut missing: Vec<_> = expected.into_keys().collect();
+    missing.sort();
+    for (client, currency) in missing {
+        mismatch(
+            client,
+            &currency,
+            "account",
+            "present".to_string(),
+            "missing".to_string(),
+        );
+    }
+    Ok(mismatches)
+}
+
+/// The name of the variant of `kind`, without its fields.
+fn error_name(kind: &ErrorKind) -> String {
+    let name = format!("{:?}", kind);
+    match name.split_once('(') {
+        Some((variant, _)) => variant.to_string(),
+        None => name,
+    }
+}
+
+/// Counters collected while processing a feed.
+#[derive(Debug, Default)]
+pub struct RunMetrics {
+    /// commands which changed the state of the engine
+    pub applied: usize,
+    /// commands of locked accounts skipped under `--fail-fast-on-lock`
+    pub skipped_locked: usize,
+    /// commands which had nothing to do
+    pub ignored: usize,
+    /// commands rejected with an error
+    pub rejected: usize,
+    /// amounts of applied commands rounded under `--clamp-precision`
+    pub rounded: usize,
+    /// clients which received at least one command, applied or not
+    pub active_clients: HashSet<ClientIdType>,
+    /// number of applied commands of every client
+    pub applied_per_client: HashMap<ClientIdType, usize>,
+    /// number of received commands of every type, applied or not
+    pub by_type: BTreeMap<String, usize>,
+    /// number of rejected commands of every error, by the name of its kind
+    pub by_error: BTreeMap<String, usize>,
+}
+
+impl RunMetrics {
+    pub fn transactions_of(&self, client_id: ClientIdType) -> usize {
+        self.applied_per_client
+            .get(&client_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn report(&self, config: &Config) {
+        if config.fail_fast_on_lock {
+            eprintln!(
+                "skipped {} commands of locked accounts",
+                self.skipped_locked
+            );
+        }
+        if self.rounded > 0 {
+            eprintln!("warning: rounded {} amounts", self.rounded);
+        }
+        if config.quiet {
+            eprintln!("rejected {} commands", self.rejected);
+        }
+    }
+
+    /// Sums the counters up together with the final state of the accounts.
+    pub async fn summary(&self, balances: &BalancesType) -> RunSummary {
+        let balances = balances.read().await;
+        let mut clients = HashSet::new();
+        let mut locked = HashSet::new();
+        for (client_id, _, balance) in balances.iter() {
+            clients.insert(client_id);
+            if balance.locked {
+                locked.insert(client_id);
+            }
+        }
+        RunSummary {
+            rows_read: self.by_type.values().sum(),
+            accepted: self.applied + self.ignored + self.skipped_locked,
+            rejected: self.rejected,
+            by_type: self.by_type.clone(),
+            by_error: self.by_error.clone(),
+            clients: clients.len(),
+            locked: locked.len(),
+        }
+    }
+}
+
+/// Outcome of a run for `--summary-json`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunSummary {
+    /// commands received by the engine, rows which cannot be parsed excluded
+    pub rows_read: usize,
+    /// commands which were not rejected, including the ignored and skipped ones
+    pub accepted: usize,
+    pub rejected: usize,
+    pub by_type: BTreeMap<String, usize>,
+    pub by_error: BTreeMap<String, usize>,
+    /// clients with an account at the end of the run
+    pub clients: usize,
+    /// clients with an account locked by a chargeback
+    pub locked: usize,
+}
+
+/// Description of a run for `--manifest`: the counters of `RunSummary`
+/// together with what was read and a fingerprint of what was written.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunManifest {
+    /// the files read in order, or the address listened on
+    pub inputs: Vec<String>,
+    #[serde(flatten)]
+    pub summary: RunSummary,
+    /// commands which changed the state of the engine
+    pub applied: usize,
+    /// `HashWriter` digest of the bytes written as the output
+    pub output_hash: String,
+}
+
+/// Token bucket of a client: up to `rate` commands at once, refilled at
+/// `rate` commands per second.
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Limits the number of commands every client may send per second.
+pub struct RateLimiter {
+    rate: NonZeroU32,
+    buckets: HashMap<ClientIdType, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: NonZeroU32) -> Self {
+        RateLimiter {
+            rate,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token from the bucket of `client_id` at time `now`, fails
+    /// if the client already used up its commands.
+    pub fn check(&mut self, client_id: ClientIdType, now: Instant) -> Result<()> {
+        let rate = f64::from(self.rate.get());
+        let bucket = self.buckets.entry(client_id).or_insert(Bucket {
+            tokens: rate,
+            refilled: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        bucket.refilled = now;
+        if bucket.tokens < 1.0 {
+            bail!(ErrorKind::RateLimited)
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// The commands of the channel with those of the priority clients first.
+/// Whatever the readers have queued is taken off the channel at once, and
+/// the commands of a priority client jump ahead of the others. Every
+/// client stays in one of the two queues, so its own commands keep their
+/// order.
+pub struct PriorityCommands {
+    egress: mpsc::UnboundedReceiver<Command>,
+    priority_clients: HashSet<ClientIdType>,
+    priority: VecDeque<Command>,
+    regular: VecDeque<Command>,
+    closed: bool,
+}
+
+impl PriorityCommands {
+    pub fn new(
+        egress: mpsc::UnboundedReceiver<Command>,
+        priority_clients: HashSet<ClientIdType>,
+    ) -> Self {
+        PriorityCommands {
+            egress,
+            priority_clients,
+            priority: VecDeque::new(),
+            regular: VecDeque::new(),
+            closed: false,
+        }
+    }
+}
+
+impl tokio_stream::Stream for PriorityCommands {
+    type Item = Command;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Command>> {
+        let this = &mut *self;
+        while !this.closed {
+            match this.egress.poll_recv(cx) {
+                Poll::Ready(Some(cmd)) if this.priority_clients.contains(&cmd.client_id) => {
+                    this.priority.push_back(cmd)
+                }
+                Poll::Ready(Some(cmd)) => this.regular.push_back(cmd),
+                Poll::Ready(None) => this.closed = true,
+                // the waker is registered, so an empty queue may wait
+                Poll::Pending => break,
+            }
+        }
+        match this
+            .priority
+            .pop_front()
+            .or_else(|| this.regular.pop_front())
+        {
+            Some(cmd) => Poll::Ready(Some(cmd)),
+            None if this.closed => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Applies the commands received from the reader until the channel is closed.
+/// With `updates`, the resulting balance of the affected client is written
+/// to the sink after every applied command. With `--priority-clients`, the
+/// commands of those clients are taken first, see `PriorityCommands`.
+pub async fn process_commands<S: OutputSink>(
+    egress: mpsc::UnboundedReceiver<Command>,
+    config: &Config,
+    transaction_history: &TransactionHistoryType,
+    balances: &BalancesType,
+    metrics: &mut RunMetrics,
+    updates: Option<S>,
+) -> Result<()> {
+    let (th, bs) = (transaction_history, balances);
+    if config.priority_clients.is_empty() {
+        let commands = tokio_stream::wrappers::UnboundedReceiverStream::new(egress);
+        process_stream(commands, config, th, bs, metrics, updates).await
+    } else {
+        let commands = PriorityCommands::new(egress, config.priority_clients.clone());
+        process_stream(commands, config, th, bs, metrics, updates).await
+    }
+}
+
+/// Applies commands which were parsed elsewhere, e.g. by an embedder's own
+/// parser, one after the other. A rejected command is counted and reported
+/// like a rejected row.
+pub async fn process_all<I>(
+    commands: I,
+    config: &Config,
+    transaction_history: &TransactionHistoryType,
+    balances: &BalancesType,
+    metrics: &mut RunMetrics,
+) -> Result<()>
+where
+    I: IntoIterator<Item = Command>,
+{
+    process_stream(
+        tokio_stream::iter(commands),
+        config,
+        transaction_history,
+        balances,
+        metrics,
+        None::<output::CsvSink<std::io::Sink>>,
+    )
+    .await
+}
+
+/// Like `process_commands`, but applies the commands of any stream until
+/// it ends.
+pub async fn process_stream<St, S>(
+    mut commands: St,
+    config: &Config,
+    transaction_history: &TransactionHistoryType,
+    balances: &BalancesType,
+    metrics: &mut RunMetrics,
+    mut updates: Option<S>,
+) -> Result<()>
+where
+    St: tokio_stream::Stream<Item = Command> + Unpin,
+    S: OutputSink,
+{
+    use tokio_stream::StreamExt;
+
+    let mut outcome = Ok(());
+    let mut limiter = config.rate_limit.map(RateLimiter::new);
+    // measured from the start of processing, a stuck input included
+    let deadline = config.timeout.map(|t| tokio::time::Instant::now() + t);
+    // transactions of the clients skipped by `filter_client`, which the
+    // filtered client may not refer to either
+    let mut filtered_out = HashSet::new();
+    loop {
+        // checked between the commands as well, the time spent applying
+        // them counts too; a command is never left half applied
+        let next = match deadline {
+            Some(deadline) if tokio::time::Instant::now() >= deadline => None,
+            Some(deadline) => tokio::time::timeout_at(deadline, commands.next())
+                .await
+                .ok(),
+            None => Some(commands.next().await),
+        };
+        let Some(next) = next else {
+            let timeout = config.timeout.unwrap_or_default();
+            outcome = Err(ErrorKind::RunTimedOut(timeout).into());
+            break;
+        };
+        let Some(cmd) = next else { break };
+        if config.filter_client.is_some_and(|id| id != cmd.client_id) {
+            if let DEPOSIT | WITHDRAWAL | ADJUST = cmd.type_.as_str() {
+                filtered_out.insert(cmd.tx_id);
+            }
+            continue;
+        }
+        metrics.active_clients.insert(cmd.client_id);
+        *metrics.by_type.entry(cmd.type_.clone()).or_default() += 1;
+        let allowed = match limiter.as_mut() {
+            Some(limiter) => limiter.check(cmd.client_id, Instant::now()),
+            None => Ok(()),
+        };
+        // the client's own transaction may share its id with a skipped one
+        let foreign = matches!(cmd.type_.as_str(), DISPUTE | RESOLVE | CHARGEBACK)
+            && filtered_out.contains(&cmd.tx_id)
+            && transaction_history
+                .read()
+                .await
+                .get_for(cmd.client_id, &cmd.tx_id)
+                .is_none();
+        let result = match allowed {
+            Ok(()) if foreign => Err(ErrorKind::ReferenceTransactionFilteredOut(cmd.tx_id).into()),
+            Ok(()) => do_cmd(&cmd, config, transaction_history, balances).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(Outcome::Skipped) => metrics.skipped_locked += 1,
+            Ok(Outcome::Ignored) => metrics.ignored += 1,
+            Ok(Outcome::Applied(applied)) => {
+                metrics.applied += 1;
+                *metrics.applied_per_client.entry(cmd.client_id).or_default() += 1;
+                let scale = config.max_scale(cmd.currency.as_deref());
+                if config.clamp_precision
+                    && cmd
+                        .amount
+                        .as_deref()
+                        .is_some_and(|n| is_over_precise(n, scale, config))
+                {
+                    metrics.rounded += 1;
+                }
+                if let Some(sink) = updates.as_mut() {
+                    let currency = applied.currency.as_deref();
+                    let mut record =
+                        AccountRecord::new(applied.client_id, currency, &applied.balance)?;
+                    if config.with_counts {
+                        record.transactions = Some(metrics.transactions_of(applied.client_id));
+                    }
+                    record.label_currency(config.currency_label.as_deref());
+                    record.restyle(config.amount_style());
+                    sink.write_account(&record).await?;
+                }
+            }
+            Err(e) => {
+                metrics.rejected += 1;
+                *metrics.by_error.entry(error_name(e.kind())).or_default() += 1;
+                if !config.quiet {
+                    eprintln!("\"{:?}\" : {}", cmd, e);
+                }
+                if let ErrorKind::ClientLimitExceeded = e.kind() {
+                    outcome = Err(e);
+                    break;
+                }
+            }
+        }
+    }
+    // lets the reader know, should the loop have stopped early
+    drop(commands);
+    if let Some(mut sink) = updates {
+        sink.finish().await?;
+    }
+    outcome
+}
+
+/// Columns of the CSV input, `timestamp` being only read by `read_merged`.
+const COLUMNS: [&str; 6] = ["type", "client", "tx", "amount", "currency", "timestamp"];
+
+/// Rejects a header naming the same column twice, serde would silently
+/// take one of them. Columns which are never read, often a sign of a typo
+/// or of a copy-paste error, are reported, and rejected under `--strict-csv`.
+fn check_headers(headers: &csv_async::StringRecord, config: &Config) -> Result<()> {
+    for (i, column) in headers.iter().enumerate() {
+        if headers.iter().skip(i + 1).any(|other| other == column) {
+            bail!(ErrorKind::DuplicateHeader(column.to_string()))
+        }
+    }
+    for column in headers.iter().filter(|c| !COLUMNS.contains(c)) {
+        let e = Error::from(ErrorKind::UnexpectedColumn(column.to_string()));
+        if config.strict_csv {
+            return Err(e);
+        }
+        eprintln!("warning: {}", e);
+    }
+    Ok(())
+}
+
+/// Parses CSV records from `reader` and forwards them to the consumer.
+/// Stops with `ConsumerTerminated` once the receiving side is gone.
+pub async fn read_commands<R>(reader: R, ingress: mpsc::UnboundedSender<Command>) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    read_commands_with(reader, &Config::default(), ingress).await
+}
+
+/// Like `read_commands`, but rows with a different number of fields than
+/// the header are rejected under `--strict-csv`, and rows which cannot be
+/// parsed are not reported under `--quiet`.
+pub async fn read_commands_with<R>(
+    reader: R,
+    config: &Config,
+    ingress: mpsc::UnboundedSender<Command>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    use tokio_stream::StreamExt;
+
+    let mut csv_rdr = csv_reader_builder(config).create_reader(reader);
+
+    let headers = csv_rdr.headers().await?.clone();
+    check_headers(&headers, config)?;
+    let header_only = !headers.is_empty();
+
+    let mut rows = 0;
+    let mut records = csv_rdr.records();
+    while let Some(record) = records.next().await {
+        let input = match record {
+            Ok(record) if is_blank(&record) => continue,
+            Ok(record) => record.deserialize::<Command>(Some(&headers)),
+            Err(e) => Err(e),
+        };
+        rows += 1;
+        match input {
+            Ok(cmd) => {
+                if ingress.send(cmd).is_err() {
+                    bail!(ErrorKind::ConsumerTerminated)
+                }
+            }
+            Err(e) if !config.quiet => eprintln!("{}", e),
+            Err(_) => {}
+        }
+    }
+    // most likely a failed export, rather than rows which were all rejected
+    if rows == 0 {
+        warn_empty(header_only);
+    }
+    Ok(())
+}
+
+/// The CSV reader of the commands. Lines starting with `--comment-char`
+/// are skipped by it, and so are empty lines.
+fn csv_reader_builder(config: &Config) -> csv_async::AsyncReaderBuilder {
+    let mut builder = csv_async::AsyncReaderBuilder::new();
+    builder
+        .flexible(!config.strict_csv)
+        .trim(Trim::All)
+        .comment(config.comment_char);
+    builder
+}
+
+/// Whether a row has nothing but whitespace, e.g. a line of spaces or
+/// a row of empty cells left by a spreadsheet.
+fn is_blank(record: &csv_async::StringRecord) -> bool {
+    record.iter().all(str::is_empty)
+}
+
+fn warn_empty(header_only: bool) {
+    if header_only {
+        eprintln!("warning: the input has a header but no rows");
+    } else {
+        eprintln!("warning: the input is empty");
+    }
+}
+
+/// A CSV source of `read_merged` with its next row.
+struct MergeSource<R> {
+    reader: csv_async::AsyncReader<BufReader<RetryReader<R>>>,
+    headers: csv_async::StringRecord,
+    timestamp: usize,
+    head: Option<(u64, csv_async::StringRecord)>,
+    /// rows read so far, blank ones aside
+    rows: usize,
+    quiet: bool,
+}
+
+impl<R> MergeSource<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    async fn new(reader: R, config: &Config) -> Result<Self> {
+        let mut reader =
+            csv_reader_builder(config).create_reader(prepare_input(reader, config).await?);
+        let headers = reader.headers().await?.clone();
+        check_headers(&headers, config)?;
+        // an empty input has no column at all, it is only warned of
+        let timestamp = match headers.iter().position(|h| h == "timestamp") {
+            Some(timestamp) => timestamp,
+            None if headers.is_empty() => 0,
+            None => bail!(ErrorKind::MissingField("timestamp")),
+        };
+        Ok(MergeSource {
+            reader,
+            headers,
+            timestamp,
+            head: None,
+            rows: 0,
+            quiet: config.quiet,
+        })
+    }
+
+    /// Reads rows until one with a timestamp is found or the source is
+    /// exhausted, reporting the rows it skips.
+    async fn advance(&mut self) -> Result<()> {
+        loop {
+            let mut record = csv_async::StringRecord::new();
+            let read = self.reader.read_record(&mut record).await;
+            if matches!(read, Ok(true)) && !is_blank(&record) {
+                self.rows += 1;
+            }
+            match read {
+                // as `read_commands_with` warns of an empty input
+                Ok(false) if self.rows == 0 => {
+                    warn_empty(!self.headers.is_empty());
+                    return Ok(());
+                }
+                Ok(false) => return Ok(()),
+                Ok(true) if is_blank(&record) => {}
+                Ok(true) => match record.get(self.timestamp).map(str::parse) {
+                    Some(Ok(timestamp)) => {
+                        self.head = Some((timestamp, record));
+                        return Ok(());
+                    }
+                    _ if self.quiet => {}
+                    Some(Err(_)) => eprintln!("{:?}: invalid timestamp", record),
+                    None => eprintln!("{:?}: {}", record, ErrorKind::MissingField("timestamp")),
+                },
+                Err(e) if e.is_io_error() => return Err(e.into()),
+                Err(e) if !self.quiet => eprintln!("{}", e),
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+/// Forwards the commands of several CSV files, each sorted by its
+/// `timestamp` column, to the consumer in the order of their timestamps.
+/// Rows with equal timestamps are taken from the earlier file first.
+pub async fn read_merged<R>(
+    readers: Vec<R>,
+    config: &Config,
+    ingress: mpsc::UnboundedSender<Command>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut sources = Vec::with_capacity(readers.len());
+    for reader in readers {
+        let mut source = MergeSource::new(reader, config).await?;
+        source.advance().await?;
+        sources.push(source);
+    }
+
+    loop {
+        let next = sources
+            .iter()
+            .enumerate()
+            .filter_map(|(i, source)| source.head.as_ref().map(|(t, _)| (*t, i)))
+            .min();
+        let Some((_, i)) = next else {
+            return Ok(());
+        };
+        let source = &mut sources[i];
+        if let Some((_, record)) = source.head.take() {
+            match record.deserialize::<Command>(Some(&source.headers)) {
+                Ok(cmd) => {
+                    if ingress.send(cmd).is_err() {
+                        bail!(ErrorKind::ConsumerTerminated)
+                    }
+                }
+                Err(e) if !config.quiet => eprintln!("{}", e),
+                Err(_) => {}
+            }
+        }
+        source.advance().await?;
+    }
+}
+
+/// Parses newline delimited JSON commands from `reader` and forwards them to the consumer.
+pub async fn read_json_lines<R>(reader: R, ingress: mpsc::UnboundedSender<Command>) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    read_json_lines_with(reader, &Config::default(), ingress).await
+}
+
+/// Like `read_json_lines`, but lines which cannot be parsed are not
+/// reported under `--quiet`.
+pub async fn read_json_lines_with<R>(
+    reader: R,
+    config: &Config,
+    ingress: mpsc::UnboundedSender<Command>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut rows = 0;
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let comment = config
+            .comment_char
+            .is_some_and(|c| line.as_bytes().first() == Some(&c));
+        if line.trim().is_empty() || comment {
+            continue;
+        }
+        rows += 1;
+        match serde_json::from_str::<Command>(&line) {
+            Ok(cmd) => {
+                if ingress.send(cmd).is_err() {
+                    bail!(ErrorKind::ConsumerTerminated)
+                }
+            }
+            Err(e) if !config.quiet => eprintln!("{}", e),
+            Err(_) => {}
+        }
+    }
+    if rows == 0 {
+        warn_empty(false);
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////////////
+
+/// Delay before the first retry of a read, doubled with every further retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Repeats the reads of `inner` which fail with a transient error, up to
+/// `max_retries` times in a row, waiting longer before every retry.
+/// Errors of the parsers above, e.g. a malformed row, are never retried.
+pub struct RetryReader<R> {
+    inner: R,
+    max_retries: u32,
+    retries: u32,
+    backoff: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<R> RetryReader<R> {
+    pub fn new(inner: R, max_retries: u32) -> Self {
+        RetryReader {
+            inner,
+            max_retries,
+            retries: 0,
+            backoff: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for RetryReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if let Some(backoff) = self.backoff.as_mut() {
+                ready!(backoff.as_mut().poll(cx));
+                self.backoff = None;
+            }
+            match ready!(Pin::new(&mut self.inner).poll_read(cx, buf)) {
+                Err(e) if is_transient(&e) && self.retries < self.max_retries => {
+                    let delay = RETRY_BACKOFF.saturating_mul(1 << self.retries.min(16));
+                    self.retries += 1;
+                    self.backoff = Some(Box::pin(tokio::time::sleep(delay)));
+                }
+                result => {
+                    self.retries = 0;
+                    return Poll::Ready(result);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any number of
+/// characters and `?` for a single one.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // the last `*` and the position in `name` it was matched up to
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // let the last `*` swallow one more character
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Opens a file to read, failing with `CannotOpen` rather than a bare I/O
+/// error, so that the message names the file.
+pub async fn open_input(path: impl AsRef<Path>) -> Result<tokio::fs::File> {
+    let path = path.as_ref();
+    tokio::fs::File::open(path).await.map_err(|e| {
+        // the OS error number adds nothing to its description
+        let reason = e.to_string();
+        let reason = reason.split(" (os error").next().unwrap_or_default();
+        ErrorKind::CannotOpen(path.display().to_string(), reason.to_string()).into()
+    })
+}
+
+/// Expands the input path into the files to read one after the other, in
+/// sorted order: the files of a directory, or those matching a file name
+/// with `*` and `?` wildcards, e.g. `data/2024-*.csv`. Any other path is
+/// returned as is.
+pub fn expand_input(input: &str) -> Result<Vec<PathBuf>> {
+    let path = Path::new(input);
+    let (dir, pattern) = if path.is_dir() {
+        (path, "*")
+    } else {
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.contains(['*', '?']) => {
+                let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+                (dir.unwrap_or(Path::new(".")), name)
+            }
+            _ => return Ok(vec![path.to_path_buf()]),
+        }
+    };
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let matches = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| wildcard_match(pattern, name));
+        if matches && entry.file_type()?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    if paths.is_empty() {
+        bail!(ErrorKind::NoInputMatched(input.to_string()))
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Leading bytes of the compressed formats an input is checked for.
+const COMPRESSION_MAGICS: [(&[u8], &str); 3] = [
+    (b"\x1f\x8b", "gzip"),
+    (b"\x28\xb5\x2f\xfd", "zstd"),
+    (b"BZh", "bzip2"),
+];
+
+/// Buffers `reader`, rejecting it if its first bytes are those of a
+/// compressed format, which would otherwise be parsed as garbled rows.
+async fn uncompressed<R>(reader: R) -> Result<BufReader<R>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let head = reader.fill_buf().await?;
+    if let Some((_, format)) = COMPRESSION_MAGICS
+        .iter()
+        .find(|(magic, _)| head.starts_with(magic))
+    {
+        bail!(ErrorKind::CompressedInput(format))
+    }
+    Ok(reader)
+}
+
+/// Wraps an input the same way whichever reader parses it: transient
+/// read errors are retried and compressed data is rejected.
+async fn prepare_input<R>(reader: R, config: &Config) -> Result<BufReader<RetryReader<R>>>
+where
+    R: AsyncRead + Unpin,
+{
+    uncompressed(RetryReader::new(reader, config.max_retries)).await
+}
+
+/// Forwards the commands of `reader`, in the configured format, to the consumer.
+pub async fn read_input<R>(
+    reader: R,
+    config: &Config,
+    ingress: mpsc::UnboundedSender<Command>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let reader = prepare_input(reader, config).await?;
+    match config.input_format {
+        InputFormat::Csv => read_commands_with(reader, config, ingress).await,
+        InputFormat::Jsonl => read_json_lines_with(reader, config, ingress).await,
+    }
+}
+
+/// Runs the whole pipeline over `reader` and returns the final balances
+/// ordered by client id and currency, detached from the shared maps.
+pub async fn process_and_collect<R>(
+    reader: R,
+    config: &Config,
+) -> Result<Vec<(ClientIdType, Currency, Balance)>>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let balances = Balances::new();
+    let transaction_history = TransactionHistory::new();
+    let (ingress, egress) = mpsc::unbounded_channel();
+
+    let mut metrics = RunMetrics::default();
+    let (read, processed) = tokio::join!(
+        read_input(reader, config, ingress),
+        process_commands(
+            egress,
+            config,
+            &transaction_history,
+            &balances,
+            &mut metrics,
+            None::<output::CsvSink<std::io::Sink>>,
+        )
+    );
+    processed?;
+    read?;
+
+    let mut collected: Vec<_> = balances
+        .read()
+        .await
+        .iter()
+        .map(|(client_id, currency, balance)| (client_id, currency.map(str::to_string), *balance))
+        .collect();
+    collected.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+    Ok(collected)
+}
+
+/// Accepts connections forever, forwarding the commands of each one to the
+/// consumer. A connection which fails or drops in the middle of a row only
+/// loses its unfinished row; every complete command has already been handed
+/// to the single consumer, which keeps the shared state consistent.
+pub async fn serve(
+    listener: TcpListener,
+    config: Config,
+    ingress: mpsc::UnboundedSender<Command>,
+) -> Result<()> {
+    let config = Arc::new(config);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let ingress = ingress.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = read_input(stream, &config, ingress).await {
+                eprintln!("{}: {}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{DecimalContext, DisputePolicy, InputFormat};
+    use crate::ledger::LedgerError;
+    use crate::output::{self, AccountRecord, OutputSink};
+    use crate::{
+        account_records, clear_history, command_amount, do_cmd, expand_input, explain,
+        held_breakdown, history_records, locked_held, parse_row, process_all, process_and_collect,
+        process_commands, process_stream, read_commands, read_commands_with, read_groups,
+        read_input, read_json_lines, read_json_lines_with, read_merged, reconcile,
+        record_transaction, reset, serve, unfreeze, verify_held_consistency, Balance, Balances,
+        BalancesType, ClientIdType, LockedHeld, Result, TransactionHistory, TransactionHistoryType,
+        TransactionIdType, MAX_SCALE,
+    };
+    use crate::{
+        open_input, wildcard_match, Applied, BalanceOperation, Command, CommandRecord, Config,
+        ErrorClass, ErrorKind, Outcome, RateLimiter, RunMetrics, TransactionKind,
+    };
+    use csv_async::Trim;
+    use rust_decimal::{Decimal, RoundingStrategy};
+    use std::collections::HashSet;
+    use std::num::NonZeroU32;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+    use tokio_stream::StreamExt;
+
+    async fn consume(th: &TransactionHistoryType, bs: &BalancesType, data: &str) -> Result<()> {
+        consume_with(&Config::default(), th, bs, data).await
+    }
+
+    async fn consume_with(
+        config: &Config,
+        th: &TransactionHistoryType,
+        bs: &BalancesType,
+        data: &str,
+    ) -> Result<()> {
+        let mut rdr = csv_async::AsyncReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .create_deserializer(data.as_bytes());
+
+        let mut records = rdr.deserialize::<Command>();
+        while let Some(input) = records.next().await {
+            match input {
+                Ok(cmd) => {
+                    do_cmd(&cmd, config, th, bs).await?;
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the available and held funds of the account of `client`, given
+    /// as decimal strings. Amounts are compared by value, `"70"` matches `70.0000`.
+    async fn assert_balance(
+        balances: &BalancesType,
+        client: ClientIdType,
+        avail: &str,
+        held: &str,
+        locked: bool,
+    ) {
+        let expected = (
+            avail.parse::<Decimal>().unwrap(),
+            held.parse::<Decimal>().unwrap(),
+            locked,
+        );
+        let b = balances.read().await;
+        let balance = b
+            .get(&client)
+            .unwrap_or_else(|| panic!("no account for client {}", client));
+        assert_eq!(
+            (balance.avail, balance.held, balance.locked),
+            expected,
+            "balance of client {}",
+            client
+        );
+    }
+
+    /// Checks that `result` failed with an error of kind `kind`.
+    macro_rules! assert_err_kind {
+        ($result:expr, $kind:pat) => {
+            match $result {
+                Ok(_) => panic!("expected {}, got Ok", stringify!($kind)),
+                Err(e) => assert!(
+                    matches!(e.0, $kind),
+                    "expected {}, got {}",
+                    stringify!($kind),
+                    e
+                ),
+            }
+        };
+    }
+
+    #[tokio::test]
+    async fn basic_deserialization_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        withdrawal, 1, 2, 500
+        deposit, 1, 3, 500
+        dispute, 1, 3
+        resolve, 1, 3",
+        )
+        .await?;
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn basic_account_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 1, 2, 500
+        withdrawal, 1, 3, 1500
+        deposit, 2, 4, 1000
+        withdrawal, 2, 5 , 1 ",
+        )
+        .await?;
+
+        let b = balances.read().await;
+        let b1 = b.get(&(1 as ClientIdType));
+        assert!(b1.is_some());
+        let b1 = b1.unwrap();
+        assert_eq!(b1.avail, Decimal::ZERO);
+        assert_eq!(b1.held, Decimal::ZERO);
+        assert!(!b1.locked);
+
+        let b2 = b.get(&(2 as ClientIdType));
+        assert!(b2.is_some());
+        let b2 = b2.unwrap();
+        assert_eq!(b2.avail, Decimal::new(999, 0));
+        assert_eq!(b1.held, Decimal::ZERO);
+        assert!(!b1.locked);
+
+        assert!((3..5).map(|x| b.get(&x)).all(|x| x.is_none()));
+
+        assert_eq!(txh.read().await.iter().count(), 5);
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        withdrawal, 1, 30, -1000",
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(e.0, ErrorKind::AmountNotPositive));
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        withdrawal, 1, 31, 0",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::AmountNotPositive));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_decimal_precision() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000.0001
+        deposit, 1, 2, 499.9999",
+        )
+        .await?;
+
+        let b = balances.read().await;
+        let b1 = b.get(&(1 as ClientIdType));
+        assert!(b1.is_some());
+        let b1 = b1.unwrap();
+        assert_eq!(b1.avail, Decimal::new(15000000, 4));
+
+        assert!(consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 3, 1000.00000
+        deposit, 1, 4, 499.99999"
+        )
+        .await
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transaction_consistency() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 1, 2, 500
+        withdrawal, 1, 3, 500
+        deposit, 2, 4, 1000
+        withdrawal, 2, 5 , 1 ",
+        )
+        .await?;
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::TransactionAlreadyExist));
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        withdrawal, 1, 1, 10",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::TransactionAlreadyExist));
+
+        assert!(consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 2",
+        )
+        .await
+        .is_ok());
+
+        assert!(consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 2"
+        )
+        .await
+        .is_ok());
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 10",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::ReferenceTransactionNotFound));
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 10",
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(e.0, ErrorKind::ReferenceTransactionNotFound));
+
+        assert!(consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 2",
+        )
+        .await
+        .is_ok());
+
+        assert!(consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        chargeback, 1, 2"
+        )
+        .await
+        .is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn balance_locking_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        dispute, 1, 1
+        chargeback, 1, 1
+        withdrawal, 1, 2 , 1",
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(e.0, ErrorKind::LockedBalance));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_limit_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            max_clients: Some(2),
+            ..Config::default()
+        };
+
+        let e = consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 2, 2, 1000
+        deposit, 1, 3, 1000
+        deposit, 3, 4, 1000",
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(e.0, ErrorKind::ClientLimitExceeded));
+        assert_eq!(balances.read().await.len(), 2);
+        assert!(balances.read().await.get(&3).is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn consumer_terminated_test() -> Result<()> {
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        egress.close();
+
+        let e = read_commands(
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000"
+                .as_bytes(),
+            ingress,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(e.0, ErrorKind::ConsumerTerminated));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn duplicate_chargeback_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        dispute, 1, 1
+        chargeback, 1, 1",
+        )
+        .await?;
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        chargeback, 1, 1",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::TransactionFinalized));
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 1",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::TransactionFinalized));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn explicit_open_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            require_explicit_open: true,
+            ..Config::default()
+        };
+
+        let e = consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::AccountNotOpen));
+        assert!(balances.read().await.get(&1).is_none());
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        open, 1, 2
+        deposit, 1, 3, 1000",
+        )
+        .await?;
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(1000, 0)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clamp_precision_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            clamp_precision: true,
+            ..Config::default()
+        };
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000.00001",
+        )
+        .await?;
+
+        let b = balances.read().await;
+        let b1 = b.get(&1).unwrap();
+        assert_eq!(b1.avail, Decimal::new(10000000, 4));
+        assert_eq!(b1.avail.scale(), 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rounded_count_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            clamp_precision: true,
+            ..Config::default()
+        };
+
+        let (ingress, egress) = tokio::sync::mpsc::unbounded_channel();
+        read_commands(
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000.00001
+        deposit, 1, 2, 10.5
+        withdrawal, 1, 3, 0.123456
+        withdrawal, 1, 4, 5000.00001"
+                .as_bytes(),
+            ingress,
+        )
+        .await?;
+
+        let mut metrics = RunMetrics::default();
+        process_commands(
+            egress,
+            &config,
+            &txh,
+            &balances,
+            &mut metrics,
+            None::<VecSink>,
+        )
+        .await?;
+
+        // the rejected withdrawal does not count
+        assert_eq!(metrics.rounded, 2);
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(10103765, 4)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn explain_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config::default();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        deposit, 2, 2, 10",
+        )
+        .await?;
+
+        let trace = explain(&parse_row("dispute, 1, 3").await?, &config, &txh, &balances).await;
+        assert_eq!(
+            trace,
+            vec![
+                "dispute of client 1, transaction 3",
+                "account: available 100, held 0, locked false",
+                "referenced transaction: not found, no transaction 3 in the history",
+                "rejected: ReferenceTransactionNotFound",
+            ]
+        );
+
+        let trace = explain(&parse_row("dispute, 1, 2").await?, &config, &txh, &balances).await;
+        assert_eq!(
+            trace[2],
+            "referenced transaction: not found, transaction 2 belongs to client 2"
+        );
+
+        let trace = explain(&parse_row("dispute, 1, 1").await?, &config, &txh, &balances).await;
+        assert_eq!(
+            trace[1..],
+            [
+                "account: available 100, held 0, locked false",
+                "referenced transaction: Deposit of 100, in dispute false, held 0, finalized false",
+                "applied as dispute of 100",
+                "account becomes: available 0, held 100, locked false",
+            ]
+        );
+        // only explained, not applied
+        assert!(!txh.read().await.get(&1).unwrap().in_dispute);
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(100, 0)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn is_in_dispute_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        deposit, 1, 2, 10
+        dispute, 1, 1
+        dispute, 1, 2
+        resolve, 1, 2",
+        )
+        .await?;
+
+        let txh = txh.read().await;
+        assert_eq!(txh.is_in_dispute(&1), Some(true));
+        assert_eq!(txh.is_in_dispute(&2), Some(false));
+        assert_eq!(txh.is_in_dispute(&3), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn process_and_collect_test() -> Result<()> {
+        let data = "\
+        type ,  client, tx, amount
+        deposit, 2, 1, 1000
+        deposit, 1, 2, 500.5
+        dispute, 2, 1, 400
+        withdrawal, 1, 3, 0.5";
+
+        let collected = process_and_collect(data.as_bytes(), &Config::default()).await?;
+        assert_eq!(collected.len(), 2);
+        assert_eq!(collected[1].2.held(), Decimal::new(400, 0));
+
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        consume(&txh, &balances, data).await?;
+
+        let mut expected = output::CsvSink::new(Vec::new());
+        output::write_accounts(&mut expected, &account_records(&balances).await?).await?;
+        let mut actual = output::CsvSink::new(Vec::new());
+        for (client_id, currency, balance) in &collected {
+            actual
+                .write_account(&AccountRecord::new(
+                    *client_id,
+                    currency.as_deref(),
+                    balance,
+                )?)
+                .await?;
+        }
+        actual.finish().await?;
+        assert_eq!(actual.into_inner(), expected.into_inner());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn foreign_reference_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        deposit, 2, 2, 100
+        dispute, 1, 1",
+        )
+        .await?;
+
+        for row in ["dispute, 2, 1", "resolve, 2, 1", "chargeback, 2, 1"] {
+            let data = format!("type, client, tx, amount\n{}", row);
+            let e = consume(&txh, &balances, &data).await.unwrap_err();
+            assert!(matches!(e.0, ErrorKind::ReferenceTransactionNotFound));
+        }
+
+        let b = balances.read().await;
+        assert_eq!(b.get(&1).unwrap().held, Decimal::new(100, 0));
+        assert_eq!(b.get(&2).unwrap().avail, Decimal::new(100, 0));
+        assert!(!b.get(&2).unwrap().locked);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_active_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        // clients left over from an earlier feed
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        deposit, 2, 2, 100
+        deposit, 3, 3, 100",
+        )
+        .await?;
+
+        let (ingress, egress) = tokio::sync::mpsc::unbounded_channel();
+        read_commands(
+            "\
+        type ,  client, tx, amount
+        deposit, 2, 4, 50
+        withdrawal, 4, 5, 10"
+                .as_bytes(),
+            ingress,
+        )
+        .await?;
+        let mut metrics = RunMetrics::default();
+        process_commands(
+            egress,
+            &Config::default(),
+            &txh,
+            &balances,
+            &mut metrics,
+            None::<VecSink>,
+        )
+        .await?;
+
+        let mut records = account_records(&balances).await?;
+        records.retain(|r| metrics.active_clients.contains(&r.client));
+        let clients: Vec<_> = records.iter().map(|r| r.client).collect();
+        // the failed withdrawal still created client 4
+        assert_eq!(clients, vec![2, 4]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn currency_scale_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let mut config = Config::default();
+        config.currency_scales.insert("USD".to_string(), 2);
+        config.currency_scales.insert("BTC".to_string(), 8);
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount, currency
+        deposit, 1, 1, 10.25, USD
+        deposit, 2, 2, 0.12345678, BTC
+        deposit, 3, 3, 1.2345,",
+        )
+        .await?;
+        let b = balances.read().await;
+        assert_eq!(
+            b.get_in(&1, Some("USD")).unwrap().avail,
+            Decimal::new(1025, 2)
+        );
+        assert_eq!(
+            b.get_in(&2, Some("BTC")).unwrap().avail,
+            Decimal::new(12345678, 8)
+        );
+        assert_eq!(b.get(&3).unwrap().avail, Decimal::new(12345, 4));
+        drop(b);
+
+        for row in [
+            "deposit, 1, 4, 10.255, USD",
+            "deposit, 2, 5, 0.123456789, BTC",
+            "deposit, 3, 6, 1.23456,",
+        ] {
+            let data = format!("type, client, tx, amount, currency\n{}", row);
+            let e = consume_with(&config, &txh, &balances, &data)
+                .await
+                .unwrap_err();
+            assert!(matches!(e.0, ErrorKind::DecimalFormatError), "{}", row);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lenient_sign_test() -> Result<()> {
+        let data = "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        deposit, 1, 2, -30
+        withdrawal, 1, 3, -5";
+
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let e = consume(&txh, &balances, data).await.unwrap_err();
+        assert!(matches!(e.0, ErrorKind::AmountNotPositive));
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(100, 0)
+        );
+
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            lenient_sign: true,
+            ..Config::default()
+        };
+        consume_with(&config, &txh, &balances, data).await?;
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(75, 0)
+        );
+        // the history keeps the reinterpreted type, so tx 2 is not disputable
+        let e = consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 2",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::ReferenceTransactionTypeIncorrect));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transaction_kind_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        withdrawal, 1, 2, 40",
+        )
+        .await?;
+        {
+            let history = txh.read().await;
+            assert_eq!(history.get(&1).unwrap().kind, TransactionKind::Deposit);
+            assert_eq!(history.get(&2).unwrap().kind, TransactionKind::Withdrawal);
+        }
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 2",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::ReferenceTransactionTypeIncorrect));
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 1, 60",
+        )
+        .await?;
+        assert!(txh.read().await.get(&1).unwrap().in_dispute);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn competing_disputes_test() -> Result<()> {
+        // 80 are left available, enough for either dispute but not for both
+        let data = "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        deposit, 1, 2, 50
+        withdrawal, 1, 3, 70
+        dispute, 1, 2
+        dispute, 1, 1";
+
+        // first come, first served
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let e = consume(&txh, &balances, data).await.unwrap_err();
+        assert!(matches!(e.0, ErrorKind::FundsInsufficientForGivenOperation));
+        {
+            let b = balances.read().await;
+            assert_eq!(b.get(&1).unwrap().avail, Decimal::new(30, 0));
+            assert_eq!(b.get(&1).unwrap().held, Decimal::new(50, 0));
+            let history = txh.read().await;
+            assert!(history.get(&2).unwrap().in_dispute);
+            assert!(!history.get(&1).unwrap().in_dispute);
+        }
+
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            dispute_policy: DisputePolicy::HoldAvailable,
+            ..Config::default()
+        };
+        consume_with(&config, &txh, &balances, data).await?;
+        {
+            let b = balances.read().await;
+            assert_eq!(b.get(&1).unwrap().avail, Decimal::ZERO);
+            assert_eq!(b.get(&1).unwrap().held, Decimal::new(80, 0));
+            let history = txh.read().await;
+            assert_eq!(history.get(&2).unwrap().held, Decimal::new(50, 0));
+            assert_eq!(history.get(&1).unwrap().held, Decimal::new(30, 0));
+        }
+        verify_held_consistency(&balances, &txh).await?;
+
+        // resolving the partial dispute releases the part it holds
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 1",
+        )
+        .await?;
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(30, 0)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dispute_policy_test() -> Result<()> {
+        let data = "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        withdrawal, 1, 2, 60
+        dispute, 1, 1";
+
+        let mut outcomes = Vec::new();
+        for policy in ["reject", "hold_available", "hold_all"] {
+            let balances = Balances::new();
+            let txh = TransactionHistory::new();
+            let config = Config {
+                dispute_policy: policy.parse()?,
+                ..Config::default()
+            };
+            let rejected = consume_with(&config, &txh, &balances, data).await.is_err();
+            verify_held_consistency(&balances, &txh).await?;
+            let balance = *balances.read().await.get(&1).unwrap();
+            let held = txh.read().await.get(&1).unwrap().held;
+            outcomes.push((rejected, balance.avail, balance.held, held));
+        }
+        assert_eq!(
+            outcomes,
+            vec![
+                (true, Decimal::new(40, 0), Decimal::ZERO, Decimal::ZERO),
+                (
+                    false,
+                    Decimal::ZERO,
+                    Decimal::new(40, 0),
+                    Decimal::new(40, 0)
+                ),
+                (
+                    false,
+                    Decimal::new(-60, 0),
+                    Decimal::new(100, 0),
+                    Decimal::new(100, 0)
+                ),
+            ]
+        );
+
+        // under `hold_all`, a chargeback leaves the shortfall as a debt
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            dispute_policy: DisputePolicy::HoldAll,
+            ..Config::default()
+        };
+        consume_with(&config, &txh, &balances, data).await?;
+        consume_with(&config, &txh, &balances, "type,client,tx\nchargeback, 1, 1").await?;
+        let balance = *balances.read().await.get(&1).unwrap();
+        assert_eq!(balance.total()?, Decimal::new(-60, 0));
+        assert!(balance.locked);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_disputes_per_tx_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            max_disputes_per_tx: Some(2),
+            ..Config::default()
+        };
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        dispute, 1, 1
+        resolve, 1, 1
+        dispute, 1, 1
+        resolve, 1, 1",
+        )
+        .await?;
+        assert_eq!(txh.read().await.get(&1).unwrap().disputes, 2);
+
+        let e = consume_with(&config, &txh, &balances, "type,client,tx\ndispute, 1, 1")
+            .await
+            .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::DisputeLimitExceeded));
+        assert!(!txh.read().await.get(&1).unwrap().in_dispute);
+
+        // unlimited by default
+        consume(&txh, &balances, "type,client,tx\ndispute, 1, 1").await?;
+        assert_eq!(txh.read().await.get(&1).unwrap().disputes, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn multi_currency_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount, currency
+        deposit, 1, 1, 100, USD
+        deposit, 1, 2, 50, EUR
+        withdrawal, 1, 3, 30, USD
+        deposit, 2, 4, 10,
+        dispute, 1, 2, , EUR",
+        )
+        .await?;
+
+        // a dispute has to name the currency of the disputed deposit
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount, currency
+        chargeback, 1, 2, , USD",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::ReferenceTransactionIncorrect));
+
+        // spending more than one currency holds is rejected
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount, currency
+        withdrawal, 1, 5, 80, USD",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::FundsInsufficientForGivenOperation));
+
+        let records = account_records(&balances).await?;
+        let summary: Vec<_> = records
+            .iter()
+            .map(|r| (r.client, r.currency.as_deref(), r.available, r.held))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, Some("EUR"), Decimal::ZERO, Decimal::new(50, 0)),
+                (1, Some("USD"), Decimal::new(70, 0), Decimal::ZERO),
+                (2, None, Decimal::new(10, 0), Decimal::ZERO),
+            ]
+        );
+
+        let mut sink = output::CsvSink::new(Vec::new()).with_currency_column(true);
+        output::write_accounts(&mut sink, &records).await?;
+        assert_eq!(
+            String::from_utf8(sink.into_inner()).unwrap(),
+            "client,available,held, total, locked, currency\n\
+             1,0,50,50,false,EUR\n\
+             1,70,0,70,false,USD\n\
+             2,10,0,10,false,\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn held_consistency_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount, currency
+        deposit, 1, 1, 100,
+        deposit, 1, 2, 50,
+        deposit, 1, 3, 20, EUR
+        deposit, 2, 4, 10,
+        dispute, 1, 1, 30
+        dispute, 1, 2
+        resolve, 1, 2
+        dispute, 1, 3, , EUR
+        dispute, 2, 4
+        chargeback, 2, 4",
+        )
+        .await?;
+        verify_held_consistency(&balances, &txh).await?;
+
+        txh.write().await.get_mut(&1).unwrap().held = Decimal::new(29, 0);
+        let e = verify_held_consistency(&balances, &txh).await.unwrap_err();
+        assert!(matches!(e.0, ErrorKind::LedgerInvariantViolated(1)));
+        txh.write().await.get_mut(&1).unwrap().held = Decimal::new(30, 0);
+        verify_held_consistency(&balances, &txh).await?;
+
+        // a held amount changed behind the back of the engine is caught too
+        balances.write().await.get_or_insert(2, None).held = Decimal::new(1, 4);
+        let e = verify_held_consistency(&balances, &txh).await.unwrap_err();
+        assert!(matches!(e.0, ErrorKind::LedgerInvariantViolated(2)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn outcome_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            fail_fast_on_lock: true,
+            ..Config::default()
+        };
+
+        let mut outcomes = Vec::new();
+        for row in [
+            "open, 1, 0",
+            "deposit, 1, 1, 100",
+            "open, 1, 0",
+            "dispute, 1, 1",
+            "chargeback, 1, 1",
+            "deposit, 1, 2, 100",
+        ] {
+            outcomes.push(do_cmd(&parse(row).await?, &config, &txh, &balances).await?);
+        }
+        let applied = |type_: &str, delta, avail, held, locked| {
+            Outcome::Applied(Applied {
+                client_id: 1,
+                type_: type_.to_string(),
+                currency: None,
+                delta: Decimal::new(delta, 0),
+                balance: Balance {
+                    avail: Decimal::new(avail, 0),
+                    held: Decimal::new(held, 0),
+                    locked,
+                    ..Balance::default()
+                },
+            })
+        };
+        assert_eq!(
+            outcomes,
+            vec![
+                applied("open", 0, 0, 0, false),
+                applied("deposit", 100, 100, 0, false),
+                Outcome::Ignored,
+                applied("dispute", 100, 0, 100, false),
+                applied("chargeback", 100, 0, 0, true),
+                Outcome::Skipped,
+            ]
+        );
+
+        // errors are still reported as such
+        let e = do_cmd(
+            &parse("withdrawal, 2, 3, 10").await?,
+            &config,
+            &txh,
+            &balances,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::FundsInsufficientForGivenOperation));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_groups_test() -> Result<()> {
+        let groups = read_groups("client, group\n1, eu\n2, us\n1, eu\n".as_bytes()).await?;
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[&1], "eu");
+
+        let e = read_groups("client,group\n1,eu\n1,us\n".as_bytes())
+            .await
+            .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::GroupConflict(1)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reconcile_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1.5
+        deposit, 2, 2, 10
+        dispute, 2, 2",
+        )
+        .await?;
+        let records = account_records(&balances).await?;
+
+        let expected = "\
+client,available,held, total, locked
+1,1.50,0,1.5,false
+2,0,10,10,false
+";
+        assert!(reconcile(expected.as_bytes(), &records).await?.is_empty());
+
+        let expected = "\
+client,available,held, total, locked
+1,1.5,0,1.5,true
+3,0,0,0,false
+";
+        let mismatches = reconcile(expected.as_bytes(), &records).await?;
+        let found: Vec<_> = mismatches.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            found,
+            vec![
+                "client 1: locked expected true, actual false",
+                "client 2: account expected missing, actual present",
+                "client 3: account expected present, actual missing",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn display_scale_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            input_scale: Some(4),
+            display_scale: Some(2),
+            ..Config::default()
+        };
+
+        let (ingress, egress) = tokio::sync::mpsc::unbounded_channel();
+        read_commands(
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1.2345
+        deposit, 1, 2, 0.0051
+        withdrawal, 1, 3, 0.00001"
+                .as_bytes(),
+            ingress,
+        )
+        .await?;
+        let mut sink = VecSink::default();
+        process_commands(
+            egress,
+            &config,
+            &txh,
+            &balances,
+            &mut RunMetrics::default(),
+            Some(&mut sink),
+        )
+        .await?;
+
+        // the input is checked against four places, the output has two
+        let available: Vec<_> = sink.records.iter().map(|r| r.available).collect();
+        assert_eq!(available, vec![Decimal::new(123, 2), Decimal::new(124, 2)]);
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(12396, 4)
+        );
+
+        let config = Config {
+            input_scale: Some(5),
+            ..config
+        };
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        withdrawal, 1, 3, 0.00001",
+        )
+        .await?;
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(123959, 5)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn with_counts_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            with_counts: true,
+            ..Config::default()
+        };
+
+        let (ingress, egress) = tokio::sync::mpsc::unbounded_channel();
+        read_commands(
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        deposit, 2, 2, 10
+        withdrawal, 1, 3, 30
+        dispute, 1, 1, 50
+        resolve, 1, 1
+        withdrawal, 2, 4, 500"
+                .as_bytes(),
+            ingress,
+        )
+        .await?;
+        let mut metrics = RunMetrics::default();
+        let mut sink = VecSink::default();
+        process_commands(
+            egress,
+            &config,
+            &txh,
+            &balances,
+            &mut metrics,
+            Some(&mut sink),
+        )
+        .await?;
+
+        // the rejected withdrawal does not count
+        assert_eq!(metrics.transactions_of(1), 4);
+        assert_eq!(metrics.transactions_of(2), 1);
+        assert_eq!(metrics.transactions_of(3), 0);
+        let counts: Vec<_> = sink.records.iter().map(|r| r.transactions).collect();
+        assert_eq!(counts, vec![Some(1), Some(1), Some(2), Some(3), Some(4)]);
+
+        let mut csv = output::CsvSink::new(Vec::new());
+        output::write_accounts(&mut csv, &sink.records[3..]).await?;
+        assert_eq!(
+            String::from_utf8(csv.into_inner()).unwrap(),
+            "client,available,held, total, locked, transactions\n\
+             1,20,50,70,false,3\n\
+             1,70,0,70,false,4\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn decimal_context_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            input_scale: Some(28),
+            decimal_context: DecimalContext {
+                precision: Some(12),
+                rounding: RoundingStrategy::MidpointNearestEven,
+            },
+            ..Config::default()
+        };
+
+        // 300 amounts of 19 decimal places, every third one withdrawn
+        let mut data = String::from("type, client, tx, amount\n");
+        for i in 1..=300u64 {
+            if i % 3 == 0 {
+                data.push_str(&format!("withdrawal, 1, {}, 0.1428571428571428571\n", i));
+            } else {
+                let amount = format!("{}.{:019}", i % 7, i * i * i * 7919);
+                data.push_str(&format!("deposit, 1, {}, {}\n", i, amount));
+            }
+        }
+        consume_with(&config, &txh, &balances, &data).await?;
+
+        // a fixed value, worked out independently with twelve digits and
+        // banker's rounding after every operation
+        let b = balances.read().await;
+        assert_eq!(b.get(&1).unwrap().avail, Decimal::new(585714286769, 9));
+
+        // the held funds are rounded like the others, in the history too
+        let config = Config {
+            decimal_context: DecimalContext {
+                precision: Some(2),
+                ..config.decimal_context
+            },
+            ..config
+        };
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 1, 2, 1.25
+        dispute, 1, 2,",
+        )
+        .await?;
+        assert_balance(&balances, 1, "1000", "1.2", false).await;
+        verify_held_consistency(&balances, &txh).await?;
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 2,",
+        )
+        .await?;
+        assert_balance(&balances, 1, "1000", "0", false).await;
+        assert_eq!(txh.read().await.is_in_dispute(&2), Some(false));
+        verify_held_consistency(&balances, &txh).await?;
+
+        // an amount with fewer digits keeps its scale
+        let context = config.decimal_context;
+        assert_eq!(context.round(Decimal::new(15, 1))?.scale(), 1);
+        assert_eq!(
+            DecimalContext {
+                precision: Some(3),
+                rounding: RoundingStrategy::ToNegativeInfinity,
+            }
+            .round(Decimal::new(-12345, 2))?,
+            Decimal::new(-124, 0)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn withdrawal_fee_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            withdrawal_fee_bps: 150,
+            fee_client: Some(99),
+            ..Config::default()
+        };
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        withdrawal, 1, 2, 200
+        withdrawal, 1, 3, 0.3333",
+        )
+        .await?;
+        {
+            let b = balances.read().await;
+            // 200 + 3 and 0.3333 + 0.0050, the fee rounded to four places
+            assert_eq!(b.get(&1).unwrap().avail, Decimal::new(7966617, 4));
+            assert_eq!(b.get(&99).unwrap().avail, Decimal::new(30050, 4));
+        }
+
+        // 790 alone would fit, but not with its fee of 11.85
+        let e = consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        withdrawal, 1, 4, 790",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::FundsInsufficientForGivenOperation));
+        let b = balances.read().await;
+        assert_eq!(b.get(&1).unwrap().avail, Decimal::new(7966617, 4));
+        assert_eq!(b.get(&99).unwrap().avail, Decimal::new(30050, 4));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fee_client_test() -> Result<()> {
+        let config = Config {
+            withdrawal_fee_bps: 100,
+            fee_client: Some(99),
+            ..Config::default()
+        };
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 99, 1, 10
+        dispute, 99, 1,
+        chargeback, 99, 1,
+        deposit, 1, 2, 1000
+        withdrawal, 1, 3, 100",
+        )
+        .await?;
+        // a locked fee account still collects the fees
+        assert_balance(&balances, 1, "899", "0", false).await;
+        assert_balance(&balances, 99, "1", "0", true).await;
+
+        // the fee account counts towards the client limit
+        let limited = Config {
+            max_clients: Some(1),
+            ..config.clone()
+        };
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let data = "\
+        type ,  client, tx, amount
+        deposit, 1, 2, 1000
+        withdrawal, 1, 3, 100";
+        let e = consume_with(&limited, &txh, &balances, data).await;
+        assert_err_kind!(e, ErrorKind::ClientLimitExceeded);
+        assert_balance(&balances, 1, "1000", "0", false).await;
+        assert!(balances.read().await.get(&99).is_none());
+
+        // and has to be opened like any other
+        let explicit = Config {
+            require_explicit_open: true,
+            ..config
+        };
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let e = consume_with(
+            &explicit,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        open, 1, 1,
+        deposit, 1, 2, 1000
+        withdrawal, 1, 3, 100",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::AccountNotOpen);
+        assert_balance(&balances, 1, "1000", "0", false).await;
+        consume_with(
+            &explicit,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        open, 99, 4,
+        withdrawal, 1, 5, 100",
+        )
+        .await?;
+        assert_balance(&balances, 99, "1", "0", false).await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn allow_types_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config::from_args(&[
+            "rust-coding-test".to_string(),
+            "--allow-types".to_string(),
+            "deposit, withdrawal".to_string(),
+            "input.csv".to_string(),
+        ])?;
+
+        let e = consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        withdrawal, 1, 2, 30
+        dispute, 1, 1",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::TransactionTypeNotAllowed));
+        assert!(!txh.read().await.get(&1).unwrap().in_dispute);
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(70, 0)
+        );
+
+        let config = Config {
+            deny_types: ["withdrawal".to_string()].into(),
+            ..Config::default()
+        };
+        let e = consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        withdrawal, 1, 3, 30",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::TransactionTypeNotAllowed));
+
+        // nor can a denied type be reached through `--lenient-sign`
+        let lenient = [
+            Config {
+                lenient_sign: true,
+                ..config
+            },
+            Config {
+                lenient_sign: true,
+                allow_types: Some(["deposit".to_string()].into()),
+                ..Config::default()
+            },
+        ];
+        for config in &lenient {
+            let e = consume_with(
+                config,
+                &txh,
+                &balances,
+                "\
+        type ,  client, tx, amount
+        deposit, 1, 4, -30",
+            )
+            .await;
+            assert_err_kind!(e, ErrorKind::TransactionTypeNotAllowed);
+        }
+        assert_balance(&balances, 1, "70", "0", false).await;
+
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limiter_test() -> Result<()> {
+        let mut limiter = RateLimiter::new(NonZeroU32::new(2).unwrap());
+        let start = Instant::now();
+
+        limiter.check(1, start)?;
+        limiter.check(1, start)?;
+        let e = limiter.check(1, start).unwrap_err();
+        assert!(matches!(e.0, ErrorKind::RateLimited));
+        // other clients have their own bucket
+        limiter.check(2, start)?;
+
+        limiter.check(1, start + Duration::from_millis(500))?;
+        assert!(limiter
+            .check(1, start + Duration::from_millis(500))
+            .is_err());
+        // the bucket does not fill beyond `rate`
+        let later = start + Duration::from_secs(10);
+        limiter.check(1, later)?;
+        limiter.check(1, later)?;
+        assert!(limiter.check(1, later).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn adjust_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            allow_adjustments: true,
+            ..Config::default()
+        };
+
+        let result = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        adjust, 1, 2, 5",
+        )
+        .await;
+        assert_err_kind!(result, ErrorKind::AdjustmentsNotAllowed);
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        adjust, 1, 2, 5.5
+        adjust, 1, 3, -150",
+        )
+        .await?;
+        assert_balance(&balances, 1, "-44.5", "0", false).await;
+        assert_eq!(
+            txh.read().await.get(&3).unwrap().kind,
+            TransactionKind::Adjustment
+        );
+
+        // adjustments cannot be disputed
+        let result = consume_with(&config, &txh, &balances, "type,client,tx\ndispute, 1, 2").await;
+        assert_err_kind!(result, ErrorKind::ReferenceTransactionTypeIncorrect);
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 2, 4, 10
+        dispute, 2, 4,
+        chargeback, 2, 4,",
+        )
+        .await?;
+        let adjust = "type,client,tx,amount\nadjust, 2, 5, 3";
+        let result = consume_with(&config, &txh, &balances, adjust).await;
+        assert_err_kind!(result, ErrorKind::LockedBalance);
+
+        let config = Config {
+            adjust_locked: true,
+            ..config
+        };
+        consume_with(&config, &txh, &balances, adjust).await?;
+        assert_balance(&balances, 2, "3", "0", true).await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lenient_amounts_test() -> Result<()> {
+        let data = "\
+        type ,  client, tx, amount
+        deposit, 1, 1, +100.0000
+        deposit, 1, 2, $100.0000
+        deposit, 1, 3, +$0.5";
+
+        let e = consume(&TransactionHistory::new(), &Balances::new(), data)
+            .await
+            .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::Decimal(_)));
+
+        let config = Config {
+            lenient_amounts: true,
+            currency_symbol: Some("$".to_string()),
+            ..Config::default()
+        };
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        consume_with(&config, &txh, &balances, data).await?;
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(2005, 1)
+        );
+
+        for amount in [
+            "$$100",
+            "++100",
+            "$+100",
+            "+-100",
+            "+$-100",
+            "$--100",
+            "-$100",
+            "$",
+            "$100.00001",
+        ] {
+            let e = consume_with(
+                &config,
+                &txh,
+                &balances,
+                &format!("type,client,tx,amount\ndeposit, 1, 9, {}", amount),
+            )
+            .await
+            .unwrap_err();
+            assert!(
+                matches!(e.0, ErrorKind::DecimalFormatError),
+                "{}: {}",
+                amount,
+                e
+            );
+        }
+
+        // negative amounts are rejected as such, or adjust the balance
+        for amount in ["-30", "$-30"] {
+            let e = consume_with(
+                &config,
+                &txh,
+                &balances,
+                &format!("type,client,tx,amount\ndeposit, 1, 9, {}", amount),
+            )
+            .await;
+            assert_err_kind!(e, ErrorKind::AmountNotPositive);
+        }
+        let config = Config {
+            allow_adjustments: true,
+            ..config
+        };
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        adjust, 1, 10, -30
+        adjust, 1, 11, $-0.5",
+        )
+        .await?;
+        assert_balance(&balances, 1, "170", "0", false).await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deposits_when_locked_test() -> Result<()> {
+        let data = "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        withdrawal, 1, 2, 60
+        dispute, 1, 1
+        chargeback, 1, 1";
+        let config = Config {
+            dispute_policy: DisputePolicy::HoldAll,
+            ..Config::default()
+        };
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        consume_with(&config, &txh, &balances, data).await?;
+        assert_balance(&balances, 1, "-60", "0", true).await;
+
+        let deposit = "type,client,tx,amount\ndeposit, 1, 3, 60";
+        let result = consume_with(&config, &txh, &balances, deposit).await;
+        assert_err_kind!(result, ErrorKind::LockedBalance);
+        assert_balance(&balances, 1, "-60", "0", true).await;
+
+        let config = Config {
+            allow_deposits_when_locked: true,
+            fail_fast_on_lock: true,
+            ..config
+        };
+        consume_with(&config, &txh, &balances, deposit).await?;
+        assert_balance(&balances, 1, "0", "0", true).await;
+
+        // the account stays locked for everything else
+        let withdrawal = "type,client,tx,amount\nwithdrawal, 1, 4, 1";
+        let cmd = parse_row("withdrawal, 1, 4, 1").await?;
+        assert!(matches!(
+            do_cmd(&cmd, &config, &txh, &balances).await?,
+            Outcome::Skipped
+        ));
+        let config = Config {
+            fail_fast_on_lock: false,
+            ..config
+        };
+        let result = consume_with(&config, &txh, &balances, withdrawal).await;
+        assert_err_kind!(result, ErrorKind::LockedBalance);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn manual_hold_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            allow_manual_holds: true,
+            ..Config::default()
+        };
+
+        let result = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        hold, 1, 2, 30",
+        )
+        .await;
+        assert_err_kind!(result, ErrorKind::ManualHoldsNotAllowed);
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        hold, 1, 2, 30",
+        )
+        .await?;
+        assert_balance(&balances, 1, "70", "30", false).await;
+        verify_held_consistency(&balances, &txh).await?;
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        release, 1, 3, 20",
+        )
+        .await?;
+        assert_balance(&balances, 1, "90", "10", false).await;
+
+        for row in ["hold, 1, 4, 90.0001", "release, 1, 4, 10.0001"] {
+            let data = format!("type, client, tx, amount\n{}", row);
+            let result = consume_with(&config, &txh, &balances, &data).await;
+            assert_err_kind!(result, ErrorKind::FundsInsufficientForGivenOperation);
+        }
+
+        // funds held by a dispute are not released by `release`
+        let result = consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 1, 50
+        release, 1, 5, 20",
+        )
+        .await;
+        assert_err_kind!(result, ErrorKind::FundsInsufficientForGivenOperation);
+        assert_balance(&balances, 1, "40", "60", false).await;
+        verify_held_consistency(&balances, &txh).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unexpected_type_test() -> Result<()> {
+        let txh = TransactionHistory::new();
+        let config = Config::default();
+        // bypasses the validation of `do_cmd`, as a command type added
+        // there but not to the later steps would
+        let cmd = Command {
+            type_: "refund".to_string(),
+            client_id: 1,
+            tx_id: 1,
+            amount: Some("1".to_string()),
+            currency: None,
+        };
+
+        let e = command_amount(&cmd, MAX_SCALE, &config, &txh)
+            .await
+            .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::InternalInconsistency(ref t) if t == "refund"));
+
+        let mut history = txh.write().await;
+        let e = record_transaction(&mut history, &cmd, Decimal::ONE, &config).unwrap_err();
+        assert!(matches!(e.0, ErrorKind::InternalInconsistency(ref t) if t == "refund"));
+        assert!(history.iter().next().is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn locked_held_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        deposit, 1, 2, 40
+        deposit, 2, 3, 10
+        dispute, 1, 1
+        dispute, 1, 2
+        chargeback, 1, 1
+        dispute, 2, 3
+        chargeback, 2, 3",
+        )
+        .await?;
+
+        let b = balances.read().await;
+        let balance = b.get(&1).unwrap();
+        assert!(balance.locked);
+        assert_eq!(balance.held, Decimal::new(40, 0));
+        drop(b);
+
+        // client 2 has nothing held anymore
+        let locked = locked_held(&balances, &txh).await?;
+        assert_eq!(
+            locked,
+            vec![LockedHeld {
+                client: 1,
+                currency: None,
+                held: Decimal::new(40, 0),
+                disputes: vec![2],
+            }]
+        );
+        assert_eq!(
+            locked[0].to_string(),
+            "client 1 is locked with 40 held by the disputes of 2"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_merged_test() -> Result<()> {
+        let first = "\
+        type, client, tx, amount, timestamp
+        deposit, 1, 1, 10, 100
+        withdrawal, 1, 3, 15, 300
+        deposit, 1, 5, 1, 300
+        deposit, 1, 6, 1, x
+        deposit, 1, 7, 1, 700";
+        let second = "\
+        timestamp, type, client, tx, amount
+        200, deposit, 1, 2, 10
+        300, deposit, 1, 4, 5
+        800, withdrawal, 1, 8, 1";
+
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        read_merged(
+            vec![first.as_bytes(), second.as_bytes()],
+            &Config::default(),
+            ingress,
+        )
+        .await?;
+        let mut order = Vec::new();
+        while let Some(cmd) = egress.recv().await {
+            order.push(cmd.tx_id);
+        }
+        // ties go to the first file, the row without a timestamp is skipped
+        assert_eq!(order, vec![1, 2, 3, 5, 4, 7, 8]);
+
+        // merged, the withdrawal of 15 comes after the second deposit; read
+        // one file after the other it would have been rejected
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config::default();
+        let (ingress, egress) = tokio::sync::mpsc::unbounded_channel();
+        read_merged(vec![first.as_bytes(), second.as_bytes()], &config, ingress).await?;
+        let mut metrics = RunMetrics::default();
+        process_commands(
+            egress,
+            &config,
+            &txh,
+            &balances,
+            &mut metrics,
+            None::<VecSink>,
+        )
+        .await?;
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(11, 0)
+        );
+
+        let e = read_merged(
+            vec!["type, client, tx, amount\ndeposit, 1, 1, 10".as_bytes()],
+            &Config::default(),
+            tokio::sync::mpsc::unbounded_channel().0,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::MissingField("timestamp")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn held_breakdown_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 1, 2, 250.5
+        deposit, 2, 3, 100
+        dispute, 1, 1
+        resolve, 1, 1
+        dispute, 1, 2",
+        )
+        .await?;
+
+        assert_eq!(
+            held_breakdown(&txh).await,
+            vec![(1, 2, Decimal::new(2505, 1))]
+        );
+
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct VecSink {
+        records: Vec<AccountRecord>,
+        finished: bool,
+    }
+
+    impl OutputSink for VecSink {
+        async fn write_account(&mut self, record: &AccountRecord) -> Result<()> {
+            self.records.push(record.clone());
+            Ok(())
+        }
+
+        async fn finish(&mut self) -> Result<()> {
+            self.finished = true;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            timeout: Some(Duration::from_millis(50)),
+            ..Config::default()
+        };
+
+        // two commands, then an input which never ends
+        let commands = vec![
+            parse_row("deposit, 1, 1, 10").await?,
+            parse_row("withdrawal, 1, 2, 4").await?,
+        ];
+        let stuck = tokio_stream::iter(commands).chain(tokio_stream::pending());
+        let mut sink = VecSink::default();
+        let mut metrics = RunMetrics::default();
+        let outcome = process_stream(
+            stuck,
+            &config,
+            &txh,
+            &balances,
+            &mut metrics,
+            Some(&mut sink),
+        )
+        .await;
+
+        assert_err_kind!(outcome, ErrorKind::RunTimedOut(_));
+        // what was processed before is kept, and the updates are flushed
+        assert_eq!(metrics.applied, 2);
+        assert!(sink.finished);
+        assert_eq!(sink.records.len(), 2);
+        assert_balance(&balances, 1, "6", "0", false).await;
+
+        // an input which is always ready, the time is spent processing it
+        let endless = tokio_stream::iter(std::iter::repeat_with(|| Command {
+            type_: "deposit".to_string(),
+            client_id: 1,
+            tx_id: 1,
+            amount: Some("10".to_string()),
+            currency: None,
+        }));
+        let outcome = process_stream(
+            endless,
+            &config,
+            &txh,
+            &balances,
+            &mut metrics,
+            None::<VecSink>,
+        )
+        .await;
+        assert_err_kind!(outcome, ErrorKind::RunTimedOut(_));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn filter_client_test() -> Result<()> {
+        let rows = [
+            "deposit, 1, 1, 10",
+            "deposit, 2, 2, 10",
+            "withdrawal, 1, 3, 4",
+            "dispute, 2, 2,",
+            "withdrawal, 2, 4, 100",
+            // tx 2 is of client 2
+            "dispute, 1, 2,",
+            // tx 5 is of both, the ids of the skipped commands are not checked
+            "deposit, 2, 5, 10",
+            "deposit, 1, 5, 3",
+            "dispute, 1, 5,",
+        ];
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            filter_client: Some(1),
+            ..Config::default()
+        };
+        let (ingress, egress) = tokio::sync::mpsc::unbounded_channel();
+        for row in rows {
+            ingress.send(parse_row(row).await?).unwrap();
+        }
+        drop(ingress);
+
+        let mut metrics = RunMetrics::default();
+        let sink = None::<output::CsvSink<std::io::Sink>>;
+        process_commands(egress, &config, &txh, &balances, &mut metrics, sink).await?;
+
+        let records = account_records(&balances).await?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].client, 1);
+        assert_balance(&balances, 1, "6", "3", false).await;
+        assert_eq!(metrics.applied, 4);
+        assert_eq!(metrics.by_error["ReferenceTransactionFilteredOut"], 1);
+        assert_eq!(metrics.active_clients, HashSet::from([1]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn priority_clients_test() -> Result<()> {
+        let rows = [
+            "deposit, 1, 1, 10",
+            "deposit, 2, 2, 10",
+            "deposit, 7, 3, 5",
+            "withdrawal, 1, 4, 3",
+            "withdrawal, 7, 5, 2",
+        ];
+        let mut orders = Vec::new();
+        for priority_clients in [HashSet::new(), HashSet::from([7])] {
+            let balances = Balances::new();
+            let txh = TransactionHistory::new();
+            let config = Config {
+                priority_clients,
+                ..Config::default()
+            };
+            // all queued before the consumer runs, as when the channel backs up
+            let (ingress, egress) = tokio::sync::mpsc::unbounded_channel();
+            for row in rows {
+                ingress.send(parse_row(row).await?).unwrap();
+            }
+            drop(ingress);
+
+            let mut sink = VecSink::default();
+            let mut metrics = RunMetrics::default();
+            process_commands(
+                egress,
+                &config,
+                &txh,
+                &balances,
+                &mut metrics,
+                Some(&mut sink),
+            )
+            .await?;
+            let order: Vec<_> = sink
+                .records
+                .iter()
+                .map(|r| (r.client, r.available))
+                .collect();
+            orders.push(order);
+        }
+
+        let d = |n| Decimal::new(n, 0);
+        assert_eq!(
+            orders[0],
+            vec![(1, d(10)), (2, d(10)), (7, d(5)), (1, d(7)), (7, d(3))]
+        );
+        // client 7 goes first, every client in its own order
+        assert_eq!(
+            orders[1],
+            vec![(7, d(5)), (7, d(3)), (1, d(10)), (2, d(10)), (1, d(7))]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn output_sink_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 2, 2, 500
+        dispute, 2, 2",
+        )
+        .await?;
+
+        let mut sink = VecSink::default();
+        output::write_accounts(&mut sink, &account_records(&balances).await?).await?;
+        assert!(sink.finished);
+
+        assert_eq!(
+            sink.records,
+            vec![
+                AccountRecord {
+                    client: 1,
+                    available: Decimal::new(1000, 0),
+                    held: Decimal::ZERO,
+                    total: Decimal::new(1000, 0),
+                    locked: false,
+                    currency: None,
+                    transactions: None,
+                    group: None,
+                },
+                AccountRecord {
+                    client: 2,
+                    available: Decimal::ZERO,
+                    held: Decimal::new(500, 0),
+                    total: Decimal::new(500, 0),
+                    locked: false,
+                    currency: None,
+                    transactions: None,
+                    group: None,
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partial_resolve_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        dispute, 1, 1
+        resolve, 1, 1, 400",
+        )
+        .await?;
+        assert_balance(&balances, 1, "400", "600", false).await;
+        assert_eq!(txh.read().await.is_in_dispute(&1), Some(true));
+
+        // more than the dispute still holds
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 1, 600.01",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::FundsInsufficientForGivenOperation);
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 1, 600",
+        )
+        .await?;
+        assert_balance(&balances, 1, "1000", "0", false).await;
+        assert_eq!(txh.read().await.is_in_dispute(&1), Some(false));
+        verify_held_consistency(&balances, &txh).await?;
+
+        // a partial chargeback locks the account, the rest stays held
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 1
+        chargeback, 1, 1, 250",
+        )
+        .await?;
+        assert_balance(&balances, 1, "0", "750", true).await;
+        assert_eq!(txh.read().await.is_in_dispute(&1), Some(true));
+        verify_held_consistency(&balances, &txh).await?;
+
+        // the rest of that dispute can still be settled on the locked account
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 1, 500
+        chargeback, 1, 1,",
+        )
+        .await?;
+        assert_balance(&balances, 1, "500", "0", true).await;
+        assert_eq!(txh.read().await.is_in_dispute(&1), Some(false));
+        verify_held_consistency(&balances, &txh).await?;
+
+        // anything else of the account stays rejected
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        withdrawal, 1, 2, 10",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::LockedBalance);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn over_resolve_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 1, 2, 500
+        dispute, 1, 1, 100",
+        )
+        .await?;
+
+        // the whole deposit, while the dispute holds only a tenth of it
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 1, 1000",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::FundsInsufficientForGivenOperation);
+        assert_balance(&balances, 1, "1400", "100", false).await;
+        assert_eq!(txh.read().await.is_in_dispute(&1), Some(true));
+
+        // without an amount, exactly what is held is restored
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 1,",
+        )
+        .await?;
+        assert_balance(&balances, 1, "1500", "0", false).await;
+        assert_eq!(txh.read().await.is_in_dispute(&1), Some(false));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn settled_dispute_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 2, 2, 1000
+        dispute, 1, 1,
+        resolve, 1, 1,
+        dispute, 2, 2,
+        chargeback, 2, 2,",
+        )
+        .await?;
+
+        // a resolved dispute can no longer be charged back
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        chargeback, 1, 1,",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::NotCurrentlyDisputed);
+        assert_balance(&balances, 1, "1000", "0", false).await;
+
+        // nor can a charged back one be resolved, the transaction being
+        // checked before the account it locked
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 2, 2,",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::TransactionFinalized);
+        assert_balance(&balances, 2, "0", "0", true).await;
+
+        // neither can a transaction which was never disputed
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 3, 3, 10
+        resolve, 3, 3,",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::NotCurrentlyDisputed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn over_chargeback_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        dispute, 1, 1, 100",
+        )
+        .await?;
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        chargeback, 1, 1, 150",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::ChargebackAmountExceeded);
+        assert_balance(&balances, 1, "900", "100", false).await;
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        chargeback, 1, 1, 60",
+        )
+        .await?;
+        assert_balance(&balances, 1, "900", "40", true).await;
+
+        // together with the first, beyond the 100 disputed
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        chargeback, 1, 1, 60",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::ChargebackAmountExceeded);
+        assert_balance(&balances, 1, "900", "40", true).await;
+
+        // exactly what is left
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        chargeback, 1, 1, 40",
+        )
+        .await?;
+        assert_balance(&balances, 1, "900", "0", true).await;
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        chargeback, 1, 1, 1",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::TransactionFinalized);
+
+        // on an account locked by another dispute, any amount is refused
+        // for the lock rather than for exceeding what is held
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 2, 2, 1000
+        deposit, 2, 3, 1000
+        dispute, 2, 2,
+        dispute, 2, 3,
+        chargeback, 2, 3,",
+        )
+        .await?;
+        for row in ["chargeback, 2, 2, 100", "chargeback, 2, 2, 5000"] {
+            let e = consume(
+                &txh,
+                &balances,
+                &format!("type, client, tx, amount\n{}", row),
+            )
+            .await;
+            assert_err_kind!(e, ErrorKind::LockedBalance);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unfreeze_test() -> Result<()> {
+        // tx 1 is still disputed when tx 2 is charged back
+        let data = "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 1, 2, 500
+        dispute, 1, 1,
+        dispute, 1, 2,
+        chargeback, 1, 2,";
+
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        consume(&txh, &balances, data).await?;
+        assert_balance(&balances, 1, "0", "1000", true).await;
+
+        unfreeze(&balances, &txh, 1, false).await?;
+        assert_balance(&balances, 1, "0", "1000", false).await;
+        assert_eq!(txh.read().await.is_in_dispute(&1), Some(true));
+        // the residual is left to its dispute
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 1,",
+        )
+        .await?;
+        assert_balance(&balances, 1, "1000", "0", false).await;
+
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        consume(&txh, &balances, data).await?;
+
+        unfreeze(&balances, &txh, 1, true).await?;
+        assert_balance(&balances, 1, "1000", "0", false).await;
+        assert_eq!(txh.read().await.is_in_dispute(&1), Some(false));
+        verify_held_consistency(&balances, &txh).await?;
+
+        let e = unfreeze(&balances, &txh, 2, true).await;
+        assert_err_kind!(e, ErrorKind::AccountNotOpen);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reuse_finalized_tx_ids_test() -> Result<()> {
+        let config = Config {
+            reuse_finalized_tx_ids: true,
+            ..Config::default()
+        };
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        deposit, 2, 2, 100
+        dispute, 1, 1,
+        chargeback, 1, 1,
+        deposit, 2, 1, 40",
+        )
+        .await?;
+        assert_balance(&balances, 2, "140", "0", false).await;
+        // the new deposit can be disputed like any other
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 2, 1,",
+        )
+        .await?;
+        assert_balance(&balances, 2, "100", "40", false).await;
+
+        // tx 2 was never charged back, tx 1 is disputed again
+        for row in ["deposit, 2, 2, 10", "deposit, 2, 1, 10"] {
+            let e = consume_with(
+                &config,
+                &txh,
+                &balances,
+                &format!("type, client, tx, amount\n{}", row),
+            )
+            .await;
+            assert_err_kind!(e, ErrorKind::TransactionAlreadyExist);
+        }
+
+        // no reuse by default
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        dispute, 1, 1,
+        chargeback, 1, 1,
+        deposit, 2, 1, 40",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::TransactionAlreadyExist);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partial_dispute_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        dispute, 1, 1, 100.5",
+        )
+        .await?;
+        {
+            let b = balances.read().await;
+            let b1 = b.get(&1).unwrap();
+            assert_eq!(b1.avail, Decimal::new(8995, 1));
+            assert_eq!(b1.held, Decimal::new(1005, 1));
+        }
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 1",
+        )
+        .await?;
+        {
+            let b = balances.read().await;
+            let b1 = b.get(&1).unwrap();
+            assert_eq!(b1.avail, Decimal::new(1000, 0));
+            assert_eq!(b1.held, Decimal::ZERO);
+        }
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 1, 100.00001",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::DecimalFormatError));
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 1, 1000.0001",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::DisputeAmountExceeded));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn json_lines_input_test() -> Result<()> {
+        let csv_balances = Balances::new();
+        consume(
+            &TransactionHistory::new(),
+            &csv_balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        withdrawal, 1, 2, 250.25
+        deposit, 2, 3, 10
+        dispute, 2, 3",
+        )
+        .await?;
+
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        read_json_lines(
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"1000"}
+            {"type":"withdrawal","client":1,"tx":2,"amount":"250.25"}
+
+            {"type":"deposit","client":2,"tx":3,"amount":"10"}
+            {"type":"dispute","client":2,"tx":3}"#
+                .as_bytes(),
+            ingress,
+        )
+        .await?;
+
+        let json_balances = Balances::new();
+        let txh = TransactionHistory::new();
+        while let Some(cmd) = egress.recv().await {
+            do_cmd(&cmd, &Config::default(), &txh, &json_balances).await?;
+        }
+
+        let csv_records = account_records(&csv_balances).await?;
+        let json_records = account_records(&json_balances).await?;
+        assert_eq!(csv_records, json_records);
+        assert_eq!(json_records.len(), 2);
+        assert_eq!("json".parse::<InputFormat>()?, InputFormat::Jsonl);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn process_all_test() -> Result<()> {
+        fn command(type_: &str, tx_id: TransactionIdType, amount: Option<&str>) -> Command {
+            Command {
+                type_: type_.to_string(),
+                client_id: 1,
+                tx_id,
+                amount: amount.map(str::to_string),
+                currency: None,
+            }
+        }
+
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let mut metrics = RunMetrics::default();
+        process_all(
+            vec![
+                command("deposit", 1, Some("100")),
+                command("withdrawal", 2, Some("30.5")),
+                command("withdrawal", 3, Some("1000")),
+                command("deposit", 4, Some("20")),
+                command("dispute", 4, None),
+            ],
+            &Config::default(),
+            &txh,
+            &balances,
+            &mut metrics,
+        )
+        .await?;
+        assert_eq!((metrics.applied, metrics.rejected), (4, 1));
+        {
+            let b = balances.read().await;
+            let balance = b.get(&1).unwrap();
+            assert_eq!(balance.avail, Decimal::new(695, 1));
+            assert_eq!(balance.held, Decimal::new(20, 0));
+        }
+
+        let stream = tokio_stream::iter(vec![command("resolve", 4, None)]);
+        process_stream(
+            stream,
+            &Config::default(),
+            &txh,
+            &balances,
+            &mut metrics,
+            None::<output::CsvSink<Vec<u8>>>,
+        )
+        .await?;
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(895, 1)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn empty_amount_test() -> Result<()> {
+        for row in ["deposit, 1, 2", "deposit, 1, 2,", "withdrawal, 1, 2,\"\""] {
+            let e = parse(row).await.unwrap_err();
+            assert!(
+                matches!(e.0, ErrorKind::MissingField("amount")),
+                "{}: {}",
+                row,
+                e
+            );
+        }
+
+        for line in [
+            r#"{"type":"deposit","client":1,"tx":2}"#,
+            r#"{"type":"deposit","client":1,"tx":2,"amount":null}"#,
+            r#"{"type":"deposit","client":1,"tx":2,"amount":""}"#,
+            r#"{"type":"withdrawal","client":1,"tx":2,"amount":" "}"#,
+        ] {
+            let e = serde_json::from_str::<Command>(line).unwrap_err();
+            assert!(
+                e.to_string().starts_with("missing field 'amount'"),
+                "{}: {}",
+                line,
+                e
+            );
+        }
+
+        // an empty amount of a dispute holds the whole deposit
+        let cmd: Command =
+            serde_json::from_str(r#"{"type":"dispute","client":1,"tx":2,"amount":""}"#).unwrap();
+        assert_eq!(cmd.amount, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn history_records_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 2, 5, 20
+        deposit, 1, 3, 1.5
+        withdrawal, 1, 1, 0.5
+        dispute, 2, 5
+        deposit, 2, 4, 3
+        dispute, 2, 4
+        resolve, 2, 4
+        deposit, 1, 2, 7
+        dispute, 1, 2
+        chargeback, 1, 2",
+        )
+        .await?;
+
+        let records = history_records(&txh).await;
+        let rows: Vec<_> = records
+            .iter()
+            .map(|r| (r.tx, r.client, r.type_, r.amount, r.in_dispute, r.finalized))
+            .collect();
+        // ordered by tx id, whatever the order of the input
+        assert_eq!(
+            rows,
+            vec![
+                (1, 1, "withdrawal", Decimal::new(5, 1), false, false),
+                (2, 1, "deposit", Decimal::new(7, 0), false, true),
+                (3, 1, "deposit", Decimal::new(15, 1), false, false),
+                (4, 2, "deposit", Decimal::new(3, 0), false, false),
+                (5, 2, "deposit", Decimal::new(20, 0), true, false),
+            ]
+        );
+        assert_eq!(records[4].held, Decimal::new(20, 0));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn history_eviction_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            max_history: Some(2),
+            ..Config::default()
+        };
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 1, 2, 100
+        dispute, 1, 1
+        deposit, 1, 3, 10
+        deposit, 1, 4, 1",
+        )
+        .await?;
+
+        {
+            let h = txh.read().await;
+            assert_eq!(h.iter().count(), 2);
+            assert!(h.contains_key(&1));
+            assert!(h.contains_key(&4));
+        }
+
+        let e = consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 2",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::ReferenceTransactionNotFound));
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        resolve, 1, 1",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incremental_output_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        let (ingress, egress) = tokio::sync::mpsc::unbounded_channel();
+        read_commands(
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 2, 2, 500
+        withdrawal, 1, 3, 5000
+        dispute, 2, 2"
+                .as_bytes(),
+            ingress,
+        )
+        .await?;
+
+        let mut sink = VecSink::default();
+        process_commands(
+            egress,
+            &Config::default(),
+            &txh,
+            &balances,
+            &mut RunMetrics::default(),
+            Some(&mut sink),
+        )
+        .await?;
+
+        // the failed withdrawal does not produce an update
+        assert_eq!(sink.records.len(), 3);
+        assert!(sink.finished);
+        assert_eq!(
+            sink.records[2],
+            AccountRecord {
+                client: 2,
+                available: Decimal::ZERO,
+                held: Decimal::new(500, 0),
+                total: Decimal::new(500, 0),
+                locked: false,
+                currency: None,
+                transactions: None,
+                group: None,
+            }
+        );
+
+        Ok(())
+    }
+
+    async fn parse(row: &str) -> Result<Command> {
+        let data = format!("type, client, tx, amount\n{}", row);
+        let mut rdr = csv_async::AsyncReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .create_deserializer(data.as_bytes());
+        let record = rdr.deserialize::<CommandRecord>().next().await.unwrap()?;
+        Command::try_from(record)
+    }
+
+    #[tokio::test]
+    async fn missing_fields_test() -> Result<()> {
+        for (row, field) in [
+            ("deposit", "client"),
+            ("deposit, 1", "tx"),
+            ("deposit, 1, 2", "amount"),
+            ("withdrawal, 1, 2,", "amount"),
+            ("dispute, 1", "tx"),
+            ("resolve, , 2", "client"),
+            ("chargeback", "client"),
+        ] {
+            let e = parse(row).await.unwrap_err();
+            assert!(
+                matches!(e.0, ErrorKind::MissingField(f) if f == field),
+                "{}: {}",
+                row,
+                e
+            );
+        }
+
+        assert!(parse("dispute, 1, 2").await.is_ok());
+        assert!(parse("deposit, 1, 2, 10").await.is_ok());
+
+        // the row is rejected by the reader, before do_cmd
+        let balances = Balances::new();
+        consume(
+            &TransactionHistory::new(),
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1
+        deposit, 2, 2, 10",
+        )
+        .await?;
+        assert!(balances.read().await.get(&1).is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn structural_validation_test() -> Result<()> {
+        let e = parse("open, 1, 0, 10").await.unwrap_err();
+        assert!(matches!(e.0, ErrorKind::UnexpectedField("amount")));
+        let e = parse("refund, 1, 2, 10").await.unwrap_err();
+        assert!(matches!(e.0, ErrorKind::UnknownTransationType));
+
+        // a partial dispute carries an amount, and so do its partial resolves
+        assert!(parse("dispute, 1, 2, 10").await.is_ok());
+        assert!(parse("resolve, 1, 2,").await.is_ok());
+        assert!(parse("resolve, 1, 2, 10").await.is_ok());
+        assert!(parse("chargeback, 1, 2, 10").await.is_ok());
+
+        // the row context comes from the reader
+        let mut rdr = csv_async::AsyncReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .create_deserializer("type, client, tx, amount\nopen, 1, 2, 10\n".as_bytes());
+        let e = rdr
+            .deserialize::<Command>()
+            .next()
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(e.to_string().contains("line 2"), "{}", e);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fail_fast_on_lock_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            fail_fast_on_lock: true,
+            ..Config::default()
+        };
+
+        let (ingress, egress) = tokio::sync::mpsc::unbounded_channel();
+        read_commands(
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        deposit, 1, 2, 10
+        dispute, 1, 1
+        chargeback, 1, 1
+        withdrawal, 1, 3, 5
+        deposit, 1, 4, 5
+        deposit, 2, 5, 5"
+                .as_bytes(),
+            ingress,
+        )
+        .await?;
+
+        let mut metrics = RunMetrics::default();
+        process_commands(
+            egress,
+            &config,
+            &txh,
+            &balances,
+            &mut metrics,
+            None::<VecSink>,
+        )
+        .await?;
+
+        assert_eq!(metrics.skipped_locked, 2);
+        let b = balances.read().await;
+        assert_eq!(b.get(&1).unwrap().avail, Decimal::new(10, 0));
+        assert_eq!(b.get(&2).unwrap().avail, Decimal::new(5, 0));
+        // skipped commands never reached the history
+        assert!(!txh.read().await.contains_key(&4));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reset_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let data = "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000";
+
+        consume(&txh, &balances, data).await?;
+        let e = consume(&txh, &balances, data).await.unwrap_err();
+        assert!(matches!(e.0, ErrorKind::TransactionAlreadyExist));
+
+        reset(&balances, &txh).await;
+        assert!(balances.read().await.is_empty());
+        assert_eq!(txh.read().await.iter().count(), 0);
+
+        consume(&txh, &balances, data).await?;
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(1000, 0)
+        );
+
+        // the history alone, the balance stays
+        clear_history(&txh).await;
+        assert_eq!(txh.read().await.iter().count(), 0);
+        assert_balance(&balances, 1, "1000", "0", false).await;
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        dispute, 1, 1",
+        )
+        .await;
+        assert_err_kind!(e, ErrorKind::ReferenceTransactionNotFound);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn withdrawal_reserve_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+        let config = Config {
+            reserve: Decimal::new(100, 0),
+            ..Config::default()
+        };
+
+        consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 1000
+        withdrawal, 1, 2, 900",
+        )
+        .await?;
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(100, 0)
+        );
+
+        let e = consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        withdrawal, 1, 3, 0.0001",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::ReserveBreach));
+
+        let e = consume_with(
+            &config,
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        withdrawal, 1, 4, 1000",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::FundsInsufficientForGivenOperation));
+        assert_eq!(
+            balances.read().await.get(&1).unwrap().avail,
+            Decimal::new(100, 0)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn balance_total_test() -> Result<()> {
+        let balance = Balance {
+            avail: Decimal::new(15, 1),
+            held: Decimal::new(5, 1),
+            ..Balance::default()
+        };
+        assert_eq!(balance.total()?, Decimal::new(2, 0));
+        assert_eq!(balance.to_string(), "1.5,0.5,2.0,false");
+
+        let balance = Balance {
+            avail: Decimal::MAX,
+            held: Decimal::new(1, 0),
+            ..Balance::default()
+        };
+        assert_eq!(balance.total(), Err(LedgerError::AmountOverflow));
+
+        Ok(())
+    }
+
+    #[test]
+    fn withdraw_all_test() -> Result<()> {
+        let balance = Balance {
+            avail: Decimal::new(1000, 0),
+            ..Balance::default()
+        };
+        let balance = balance.withdrawal(Decimal::new(10000000, 4))?;
+        assert_eq!(balance.avail, Decimal::ZERO);
+        assert_eq!(balance.to_string(), "0,0,0,false");
+
+        Ok(())
+    }
+
+    /// xorshift64 seeded generator of amounts with up to four decimal
+    /// places, as in `tests/fuzz.rs`, since no property testing crate is used.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn amount(&mut self) -> Decimal {
+            let scale = (self.next() % 5) as u32;
+            Decimal::new((self.next() % 1_000_000_000) as i64 + 1, scale)
+        }
+
+        fn amounts(&mut self) -> Vec<Decimal> {
+            (0..=self.next() % 8).map(|_| self.amount()).collect()
+        }
+    }
+
+    const PROPERTY_CASES: usize = 500;
+
+    #[test]
+    fn balance_properties_test() -> Result<()> {
+        let mut rng = Rng(0x5eed_ba1a_4ce5);
+        for _ in 0..PROPERTY_CASES {
+            let start = Balance {
+                avail: rng.amount(),
+                ..Balance::default()
+            };
+
+            // withdrawing what was deposited returns to the start
+            let deposits = rng.amounts();
+            let mut balance = start;
+            for &amount in &deposits {
+                balance = balance.deposit(amount)?;
+            }
+            let mut remaining: Decimal = deposits.iter().sum();
+            while remaining > Decimal::ZERO {
+                let amount = rng.amount().min(remaining);
+                balance = balance.withdrawal(amount)?;
+                remaining -= amount;
+            }
+            assert_eq!(balance, start);
+
+            // a resolved dispute changes nothing
+            let amount = rng.amount().min(start.avail);
+            let disputed = start.dispute(amount)?;
+            assert_eq!(disputed.total()?, start.total()?);
+            assert_eq!(disputed.resolve(amount)?, start);
+
+            // a chargeback takes the disputed amount and locks the account
+            let charged_back = disputed.chargeback(amount)?;
+            assert_eq!(charged_back.total()?, start.total()? - amount);
+            assert_eq!(charged_back.held, Decimal::ZERO);
+            assert!(charged_back.locked);
+
+            // whatever succeeds, neither amount goes negative
+            let mut balance = start;
+            for _ in 0..32 {
+                let amount = rng.amount();
+                let next = match rng.next() % 5 {
+                    0 => balance.deposit(amount),
+                    1 => balance.withdrawal(amount),
+                    2 => balance.dispute(amount),
+                    3 => balance.resolve(amount),
+                    _ => balance.chargeback(amount),
+                };
+                if let Ok(next) = next {
+                    balance = next;
+                }
+                assert!(balance.held >= Decimal::ZERO, "{:?}", balance);
+                assert!(balance.avail >= Decimal::ZERO, "{:?}", balance);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn amount_overflow_test() -> Result<()> {
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 79228162514264337593543950335
+        dispute, 1, 1,",
+        )
+        .await?;
+
+        let e = consume(
+            &txh,
+            &balances,
+            "\
+        type ,  client, tx, amount
+        deposit, 1, 2, 1",
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::AmountOverflow));
+        assert_eq!(balances.read().await.get(&1).unwrap().avail, Decimal::ZERO);
+        assert!(!txh.read().await.contains_key(&2));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn strict_csv_test() -> Result<()> {
+        let data = "\
+        type ,  client, tx, amount
+        deposit, 1, 1, 100
+        deposit, 1, 2
+        deposit, 1, 3, 10, 20
+        dispute, 1, 1,";
+
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        read_commands(data.as_bytes(), ingress).await?;
+        let mut flexible = Vec::new();
+        while let Some(cmd) = egress.recv().await {
+            flexible.push(cmd.tx_id);
+        }
+        // the short row is missing its amount, the long one is accepted
+        assert_eq!(flexible, vec![1, 3, 1]);
+
+        let config = Config {
+            strict_csv: true,
+            ..Config::default()
+        };
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        read_commands_with(data.as_bytes(), &config, ingress).await?;
+        let mut strict = Vec::new();
+        while let Some(cmd) = egress.recv().await {
+            strict.push(cmd.tx_id);
+        }
+        assert_eq!(strict, vec![1, 1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn comment_char_test() -> Result<()> {
+        let data = "\
+# exported by hand
+type, client, tx, amount
+deposit, 1, 1, 100
+
+# a refund, see the ticket
+deposit, 1, 2, 5
+\x20\x20
+ , , ,
+withdrawal, 1, 3, 20
+#withdrawal, 1, 4, 20
+";
+        let config = Config {
+            comment_char: Some(b'#'),
+            ..Config::default()
+        };
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        read_commands_with(data.as_bytes(), &config, ingress).await?;
+        let mut read = Vec::new();
+        while let Some(cmd) = egress.recv().await {
+            read.push(cmd.tx_id);
+        }
+        assert_eq!(read, vec![1, 2, 3]);
+
+        // the same lines in JSON
+        let data = "\
+# exported by hand
+{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"100\"}
+
+#{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":\"100\"}
+";
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        read_json_lines_with(data.as_bytes(), &config, ingress).await?;
+        assert_eq!(egress.recv().await.unwrap().tx_id, 1);
+        assert!(egress.recv().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compressed_input_test() -> Result<()> {
+        // the first bytes of `type, client, tx, amount` compressed by each tool
+        let inputs: [(&[u8], &str); 3] = [
+            (b"\x1f\x8b\x08\x00\x00\x00\x00\x00", "gzip"),
+            (b"\x28\xb5\x2f\xfd\x24\x19\xc9\x00", "zstd"),
+            (b"BZh91AY&SY", "bzip2"),
+        ];
+        for (data, format) in inputs {
+            let (ingress, _egress) = tokio::sync::mpsc::unbounded_channel();
+            let e = read_input(data, &Config::default(), ingress)
+                .await
+                .unwrap_err();
+            assert!(matches!(e.0, ErrorKind::CompressedInput(f) if f == format));
+        }
+
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 10\n";
+        read_input(data.as_bytes(), &Config::default(), ingress).await?;
+        assert_eq!(egress.recv().await.unwrap().tx_id, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn duplicate_header_test() -> Result<()> {
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        let e = read_commands(
+            "type, client, tx, amount, amount\ndeposit, 1, 1, 10, 20\n".as_bytes(),
+            ingress,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::DuplicateHeader(ref column) if column == "amount"));
+        assert_eq!(e.to_string(), "duplicate column 'amount'");
+        assert!(egress.recv().await.is_none());
+
+        let (ingress, _egress) = tokio::sync::mpsc::unbounded_channel();
+        let e = read_commands(
+            "type, client, tx, client, amount\ndeposit, 1, 1, 2, 10\n".as_bytes(),
+            ingress,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::DuplicateHeader(ref column) if column == "client"));
+
+        // a stray column is only reported, unless under `--strict-csv`
+        let data = "type, client, tx, amount, note\ndeposit, 1, 1, 10, refund\n";
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        read_commands(data.as_bytes(), ingress).await?;
+        assert_eq!(egress.recv().await.unwrap().tx_id, 1);
+
+        let config = Config {
+            strict_csv: true,
+            ..Config::default()
+        };
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        let e = read_commands_with(data.as_bytes(), &config, ingress)
+            .await
+            .unwrap_err();
+        assert!(matches!(e.0, ErrorKind::UnexpectedColumn(ref column) if column == "note"));
+        assert!(egress.recv().await.is_none());
+
+        Ok(())
+    }
+
+    /// Fails the first `failures` reads with `TimedOut`, then reads `data`.
+    struct Flaky {
+        failures: usize,
+        data: &'static [u8],
+    }
+
+    impl tokio::io::AsyncRead for Flaky {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                let e = std::io::Error::from(std::io::ErrorKind::TimedOut);
+                return std::task::Poll::Ready(Err(e));
+            }
+            std::pin::Pin::new(&mut self.data).poll_read(cx, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_test() -> Result<()> {
+        let data = b"type, client, tx, amount\ndeposit, 1, 1, 10\n";
+
+        let config = Config {
+            max_retries: 2,
+            ..Config::default()
+        };
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        read_input(Flaky { failures: 2, data }, &config, ingress).await?;
+        assert_eq!(egress.recv().await.unwrap().tx_id, 1);
+
+        let (ingress, _egress) = tokio::sync::mpsc::unbounded_channel();
+        let e = read_input(Flaky { failures: 3, data }, &config, ingress)
+            .await
+            .unwrap_err();
+        assert_eq!(e.class(), ErrorClass::Transient);
+
+        // merged inputs are retried the same way
+        let data = b"type, client, tx, amount, timestamp\ndeposit, 1, 1, 10, 5\n";
+        let (ingress, mut egress) = tokio::sync::mpsc::unbounded_channel();
+        let readers = vec![
+            Flaky { failures: 2, data },
+            Flaky {
+                failures: 1,
+                data: b"",
+            },
+        ];
+        read_merged(readers, &config, ingress).await?;
+        assert_eq!(egress.recv().await.unwrap().tx_id, 1);
+        assert!(egress.recv().await.is_none());
+
+        // a malformed header is not worth retrying
+        let (ingress, _egress) = tokio::sync::mpsc::unbounded_channel();
+        let data = b"type, client, client\n";
+        let e = read_input(Flaky { failures: 0, data }, &config, ingress)
+            .await
+            .unwrap_err();
+        assert_eq!(e.class(), ErrorClass::Fatal);
+
+        Ok(())
+    }
+
+    #[test]
+    fn expand_input_test() -> Result<()> {
+        assert!(wildcard_match("2024-*.csv", "2024-01.csv"));
+        assert!(wildcard_match("2024-*.csv", "2024-.csv"));
+        assert!(wildcard_match("*-0?.csv", "2024-01-02-03.csv"));
+        assert!(!wildcard_match("2024-*.csv", "2024-01.csv.bak"));
+        assert!(!wildcard_match("2024-??.csv", "2024-1.csv"));
+
+        let dir = std::env::temp_dir().join(format!("ithaca-expand-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("2024-sub.csv"))?;
+        for name in ["2024-02.csv", "2024-01.csv", "2023-12.csv", "notes.txt"] {
+            std::fs::write(dir.join(name), "")?;
+        }
+        let pattern = dir.join("2024-*.csv");
+        let expanded = expand_input(pattern.to_str().unwrap());
+        let listed = expand_input(dir.to_str().unwrap());
+        let unmatched = expand_input(dir.join("2025-*.csv").to_str().unwrap());
+        std::fs::remove_dir_all(&dir)?;
+
+        // directories are skipped
+        assert_eq!(
+            expanded?,
+            vec![dir.join("2024-01.csv"), dir.join("2024-02.csv")]
+        );
+        assert_eq!(listed?.len(), 4);
+        assert_err_kind!(unmatched, ErrorKind::NoInputMatched(_));
+        // a plain path is left to fail when it is opened
+        assert_eq!(
+            expand_input("missing.csv")?,
+            vec![PathBuf::from("missing.csv")]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_input_test() -> Result<()> {
+        let path = std::env::temp_dir().join("ithaca-open-input-missing.csv");
+
+        let e = open_input(&path).await.unwrap_err();
+        assert!(matches!(e.0, ErrorKind::CannotOpen(ref p, _) if *p == path.display().to_string()));
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "cannot open '{}': No such file or directory",
+                path.display()
+            )
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listen_test() -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::{TcpListener, TcpStream};
+
+        let balances = Balances::new();
+        let txh = TransactionHistory::new();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (ingress, egress) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(serve(listener, Config::default(), ingress));
+        {
+            let (txh, balances) = (txh.clone(), balances.clone());
+            tokio::spawn(async move {
+                let mut metrics = RunMetrics::default();
+                let config = Config::default();
+                process_commands(
+                    egress,
+                    &config,
+                    &txh,
+                    &balances,
+                    &mut metrics,
+                    None::<VecSink>,
+                )
+                .await
+            });
+        }
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"type,client,tx,amount\ndeposit,1,1,1000\nwithdrawal,1,2,")
+            .await?;
+        // the connection drops in the middle of a row
+        drop(stream);
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream
+            .write_all(b"type,client,tx,amount\ndeposit,1,3,5\n")
+            .await?;
+        stream.shutdown().await?;
+
+        let expected = Decimal::new(1005, 0);
+        for _ in 0..100 {
+            if balances.read().await.get(&1).map(|b| b.avail) == Some(expected) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(balances.read().await.get(&1).unwrap().avail, expected);
+        assert!(!txh.read().await.contains_key(&2));
+
+        Ok(())
+    }
+}
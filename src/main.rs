@@ -1,683 +1,375 @@
-use core::fmt;
-use rust_decimal::Decimal;
 use std::collections::HashMap;
 use std::env;
-use std::fmt::Formatter;
-
-use csv_async::Trim;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::sync::Arc;
-use tokio::fs::File;
-use tokio::sync::RwLock;
-
-use serde_derive::Deserialize;
+use std::time::Duration;
 
+use tokio::fs::File;
+use tokio::net::TcpListener;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 
-#[macro_use]
-extern crate error_chain;
-
-error_chain! {
-
-    errors{
-         AmountNotPositive{}
-         LockedBalance{}
-         FundsInsufficientForGivenOperation{}
-         InvalidArgument{}
-         UnknownTransationType{}
-         DecimalFormatError{}
-         TransactionAlreadyExist{}
-         TransactionAlreadyInDispute{}
-         ReferenceTransactionTypeIncorrect{}
-         ReferenceTransactionNotFound{}
-         ReferenceTransactionIncorrect{}
-         ReferenceTransactionStateIncorrect{}
-    }
-    foreign_links{
-        Io(::std::io::Error);
-        Decimal(::rust_decimal::Error);
-        CSV(csv_async::Error);
-    }
-}
-
-type CommandType = String;
-type ClientIdType = u16;
-type TransactionIdType = u32;
-
-#[derive(Debug, Deserialize)]
-struct Command {
-    #[serde(rename = "type")]
-    type_: String,
-    #[serde(rename = "client")]
-    client_id: ClientIdType,
-    #[serde(rename = "tx")]
-    tx_id: TransactionIdType,
-    #[serde(rename = "amount")]
-    amount: Option<String>,
-}
-
-type AmountType = Option<Decimal>;
-const ZERO_AMOUNT: Decimal = Decimal::ZERO;
-
-struct Transaction {
-    type_: CommandType,
-    client_id: ClientIdType,
-    amount: AmountType,
-    pub in_dispute: bool,
-}
-type TransactionHistoryType = Arc<RwLock<HashMap<TransactionIdType, Transaction>>>;
-struct TransactionHistory;
-impl TransactionHistory {
-    pub fn new() -> TransactionHistoryType {
-        Arc::new(RwLock::new(HashMap::new()))
-    }
-}
-
-trait BalanceOperation
-where
-    Self: Sized,
-{
-    fn deposit(&self, amount: Decimal) -> Result<Self>;
-    fn withdrawal(&self, amount: Decimal) -> Result<Self>;
-    fn dispute(&self, amount: Decimal) -> Result<Self>;
-    fn resolve(&self, amount: Decimal) -> Result<Self>;
-    fn chargeback(&self, amount: Decimal) -> Result<Self>;
-}
-
-const DEPOSIT: &str = "deposit";
-const WITHDRAWAL: &str = "withdrawal";
-const DISPUTE: &str = "dispute";
-const RESOLVE: &str = "resolve";
-const CHARGEBACK: &str = "chargeback";
-
-#[derive(Copy, Clone)]
-struct Balance {
-    avail: Decimal,
-    held: Decimal,
-    locked: bool,
-}
-
-impl Balance {
-    pub fn new() -> Self {
-        Self {
-            avail: ZERO_AMOUNT,
-            held: ZERO_AMOUNT,
-            locked: false,
+use rust_coding_test::config::{self, Config};
+use rust_coding_test::output::{
+    self, CsvSink, HashWriter, JsonSink, MultiWriter, OutputFormat, TableSink, UNGROUPED,
+};
+use rust_coding_test::{
+    account_records, expand_input, explain, held_breakdown, history_records, locked_held,
+    open_input, parse_row, process_commands, read_groups, read_input, read_merged, reconcile,
+    serve, verify_held_consistency, Balances, BalancesType, ClientIdType, ErrorKind, Result,
+    RunManifest, RunMetrics, TransactionHistory, TransactionHistoryType,
+};
+
+type Outputs = MultiWriter<Box<dyn Write + Send>>;
+
+/// Exit status of a run stopped by `--timeout`, as of coreutils' `timeout`.
+const TIMEOUT_EXIT_CODE: i32 = 124;
+/// Exit status of a run whose files cannot be opened, `EX_NOINPUT` of sysexits.
+const NO_INPUT_EXIT_CODE: i32 = 66;
+/// Exit status of a run stopped by `--max-clients`, `EX_DATAERR` of sysexits.
+const CLIENT_LIMIT_EXIT_CODE: i32 = 65;
+
+/// Opens a file to read, exiting with a plain message if it cannot be,
+/// since the error chain would not tell the user more.
+async fn open(path: impl AsRef<Path>) -> File {
+    match open_input(path).await {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            exit(NO_INPUT_EXIT_CODE);
         }
     }
 }
-impl fmt::Display for Balance {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{},{},{},{}",
-            self.avail,
-            self.held,
-            self.avail + self.held,
-            self.locked
-        )
-    }
-}
-
-fn bail_if_locked(balance: &Balance) -> Result<()> {
-    if balance.locked {
-        bail!(ErrorKind::LockedBalance)
-    } else {
-        Ok(())
-    }
-}
-
-fn check_amount(amount: Decimal) -> Result<()> {
-    if amount <= ZERO_AMOUNT {
-        Err(ErrorKind::AmountNotPositive.into())
-    } else {
-        Ok(())
-    }
-}
-
-fn to_decimal(n: &str) -> Result<Decimal> {
-    let d = Decimal::from_str_radix(n, 10)?;
-    if d.scale() > 4 {
-        Err(ErrorKind::DecimalFormatError.into())
-    } else {
-        Ok(d)
-    }
-}
-
-impl BalanceOperation for Balance {
-    fn deposit(&self, amount: Decimal) -> Result<Self> {
-        bail_if_locked(self)?;
-
-        Ok(Balance {
-            avail: self.avail + amount,
-            ..*self
-        })
-    }
-
-    fn withdrawal(&self, amount: Decimal) -> Result<Self> {
-        bail_if_locked(self)?;
 
-        if self.avail < amount {
-            Err(ErrorKind::FundsInsufficientForGivenOperation.into())
-        } else {
-            Ok(Balance {
-                avail: self.avail - amount,
-                ..*self
-            })
+/// Lists the files of the input, exiting like `open` if there are none.
+fn expand(input: &str) -> Vec<PathBuf> {
+    match expand_input(input) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            exit(NO_INPUT_EXIT_CODE);
         }
     }
-
-    fn dispute(&self, amount: Decimal) -> Result<Self> {
-        bail_if_locked(self)?;
-
-        if self.avail < amount {
-            Err(ErrorKind::FundsInsufficientForGivenOperation.into())
-        } else {
-            Ok(Balance {
-                avail: self.avail - amount,
-                held: self.held + amount,
-                ..*self
-            })
-        }
-    }
-
-    fn resolve(&self, amount: Decimal) -> Result<Self> {
-        bail_if_locked(self)?;
-
-        if self.held < amount {
-            Err(ErrorKind::FundsInsufficientForGivenOperation.into())
-        } else {
-            Ok(Balance {
-                avail: self.avail + amount,
-                held: self.held - amount,
-                ..*self
-            })
-        }
-    }
-
-    fn chargeback(&self, amount: Decimal) -> Result<Self> {
-        bail_if_locked(self)?;
-
-        if self.held < amount {
-            Err(ErrorKind::FundsInsufficientForGivenOperation.into())
-        } else {
-            Ok(Balance {
-                avail: self.avail,
-                held: self.held - amount,
-                locked: true,
-            })
-        }
-    }
-}
-
-type BalancesType = Arc<RwLock<HashMap<ClientIdType, Balance>>>;
-struct Balances;
-impl Balances {
-    fn new() -> BalancesType {
-        Arc::new(RwLock::new(HashMap::new()))
-    }
 }
 
-async fn do_cmd(
-    cmd: &Command,
-    transaction_history: &TransactionHistoryType,
+/// Writes the balances to `out`, which is `None` in incremental mode since
+/// every balance change has already been written. With `groups`, the
+/// accounts are followed by the totals of every group.
+async fn write_summary(
+    config: &Config,
     balances: &BalancesType,
+    transaction_history: &TransactionHistoryType,
+    metrics: &RunMetrics,
+    groups: Option<&HashMap<ClientIdType, String>>,
+    out: Option<Outputs>,
 ) -> Result<()> {
-    // check the transaction logic first
-    {
-        let guard = transaction_history.read().await;
-
-        match cmd.type_.as_str() {
-            DEPOSIT | WITHDRAWAL => {
-                if guard.contains_key(&cmd.tx_id) {
-                    bail!(ErrorKind::TransactionAlreadyExist)
-                }
-            }
-            DISPUTE => {
-                if let Some(tx) = guard.get(&cmd.tx_id) {
-                    if tx.type_.as_str() != DEPOSIT {
-                        bail!(ErrorKind::ReferenceTransactionTypeIncorrect);
-                    }
-                    if tx.client_id != cmd.client_id {
-                        bail!(ErrorKind::ReferenceTransactionIncorrect);
-                    }
-                    if tx.in_dispute {
-                        bail!(ErrorKind::TransactionAlreadyInDispute);
-                    }
-                } else {
-                    bail!(ErrorKind::ReferenceTransactionNotFound)
-                }
+    if let Some(out) = out {
+        let mut records = account_records(balances).await?;
+        if config.only_active {
+            records.retain(|r| metrics.active_clients.contains(&r.client));
+        }
+        for record in &mut records {
+            record.label_currency(config.currency_label.as_deref());
+        }
+        if config.with_counts {
+            for record in &mut records {
+                record.transactions = Some(metrics.transactions_of(record.client));
             }
-
-            RESOLVE | CHARGEBACK => {
-                if let Some(tx) = guard.get(&cmd.tx_id) {
-                    if !tx.in_dispute {
-                        bail!(ErrorKind::ReferenceTransactionStateIncorrect);
-                    }
-                } else {
-                    return Err(ErrorKind::ReferenceTransactionNotFound.into());
-                }
+        }
+        if let Some(groups) = groups {
+            for record in &mut records {
+                let group = groups.get(&record.client).map_or(UNGROUPED, String::as_str);
+                record.group = Some(group.to_string());
             }
-            _ => return Err(ErrorKind::UnknownTransationType.into()),
         }
-    }
-    // check if amount is available for an operation
-    if let Some(amount) = match cmd.type_.as_str() {
-        DISPUTE | RESOLVE | CHARGEBACK => transaction_history
-            .read()
-            .await
-            .get(&cmd.tx_id)
-            .and_then(|tx| tx.amount),
-        DEPOSIT | WITHDRAWAL => match &cmd.amount {
-            Some(q) => Some(to_decimal(q.as_str())?),
-            None => None,
-        },
-        _ => unreachable!(),
-    } {
-        // execute balance change
-        check_amount(amount)?;
-        let client_id = cmd.client_id;
-        let mut p = balances.write().await;
-        let balance = p.entry(client_id).or_insert_with(Balance::new);
-        let new_balance = match cmd.type_.as_str() {
-            DEPOSIT => balance.deposit(amount)?,
-            WITHDRAWAL => balance.withdrawal(amount)?,
-            DISPUTE => balance.dispute(amount)?,
-            RESOLVE => balance.resolve(amount)?,
-            CHARGEBACK => balance.chargeback(amount)?,
-            _ => unreachable!(),
+        // summed before the amounts are rounded for display
+        let mut totals = match (config.summary_only, groups) {
+            (false, None) => Vec::new(),
+            _ => output::totals(&records)?,
         };
-        p.insert(client_id, new_balance);
+        let mut footer = if config.totals {
+            output::grand_totals(&records)?
+        } else {
+            Vec::new()
+        };
+        totals
+            .iter_mut()
+            .chain(footer.iter_mut())
+            .for_each(|t| t.restyle(config.amount_style()));
 
-        {
-            // insert into or update the history
-            let mut guard = transaction_history.write().await;
-            match cmd.type_.as_str() {
-                DISPUTE => {
-                    guard.entry(cmd.tx_id).and_modify(|tx| tx.in_dispute = true);
+        if config.summary_only {
+            output::write_totals(out, &totals, config.format)?;
+        } else {
+            config.sort_by.sort(&mut records);
+            records
+                .iter_mut()
+                .for_each(|r| r.restyle(config.amount_style()));
+            let mut out = match config.format {
+                OutputFormat::Csv => {
+                    let currency_column = records.iter().any(|r| r.currency.is_some());
+                    let mut sink = CsvSink::new(out)
+                        .with_currency_column(currency_column)
+                        .with_counts_column(config.with_counts)
+                        .with_bool_format(config.bool_format);
+                    output::write_accounts(&mut sink, &records).await?;
+                    if config.totals {
+                        sink.write_footer(&footer)?;
+                    }
+                    sink.into_inner()
+                }
+                OutputFormat::Json => {
+                    let mut sink = JsonSink::new(out);
+                    output::write_accounts(&mut sink, &records).await?;
+                    sink.into_inner()
                 }
-                RESOLVE | CHARGEBACK => {
-                    guard
-                        .entry(cmd.tx_id)
-                        .and_modify(|tx| tx.in_dispute = false);
+                OutputFormat::Table => {
+                    let mut sink = TableSink::new(out);
+                    output::write_accounts(&mut sink, &records).await?;
+                    sink.into_inner()
                 }
-                DEPOSIT | WITHDRAWAL => {
-                    guard.insert(
-                        cmd.tx_id,
-                        Transaction {
-                            type_: cmd.type_.clone(),
-                            client_id: cmd.client_id,
-                            amount: Some(amount),
-                            in_dispute: false,
-                        },
-                    );
+            };
+            if groups.is_some() {
+                // a blank line separates the two CSV sections
+                if config.format == OutputFormat::Csv {
+                    writeln!(out)?;
                 }
-                _ => unreachable!(),
+                output::write_totals(out, &totals, config.format)?;
             }
         }
-        Ok(())
-    } else {
-        Err(ErrorKind::UnknownTransationType.into())
     }
+
+    if let Some(path) = &config.held_report {
+        let mut report = String::from("client,tx,held\n");
+        for (client_id, tx_id, amount) in held_breakdown(transaction_history).await {
+            report.push_str(&format!("{},{},{}\n", client_id, tx_id, amount));
+        }
+        tokio::fs::write(path, report).await?;
+    }
+    if let Some(path) = &config.dump_history {
+        let records = history_records(transaction_history).await;
+        let out = std::fs::File::create(path)?;
+        output::write_history(std::io::BufWriter::new(out), &records, config.format)?;
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    use tokio_stream::StreamExt;
-
     let args: Vec<String> = env::args().collect();
-    if args.len() > 2 {
-        eprintln!("Usage: {} filename", args[0]);
-        exit(-1);
-    }
-    let (ingress, mut egress) = mpsc::unbounded_channel();
-    let h: JoinHandle<Result<()>> = tokio::spawn(async move {
-        let reader = File::open(&args[1]).await?;
-
-        let mut csv_rdr = csv_async::AsyncReaderBuilder::new()
-            .flexible(true)
-            .trim(Trim::All)
-            .create_deserializer(reader);
-
-        let mut records = csv_rdr.deserialize::<Command>();
-        while let Some(input) = records.next().await {
-            match input {
-                Ok(cmd) => {
-                    ingress.send(cmd).unwrap();
-                }
-                Err(e) => eprintln!("{}", e),
+    let config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(_) => {
+            eprintln!("Usage: {} {}", args[0], config::USAGE);
+            exit(-1);
+        }
+    };
+    // parsed up front, so that a bad row fails before any processing
+    let explained = match &config.explain {
+        Some(row) => match parse_row(row).await {
+            Ok(cmd) => Some(cmd),
+            Err(e) => {
+                eprintln!("error: invalid --explain row: {}", e);
+                eprintln!("Usage: {} {}", args[0], config::USAGE);
+                exit(-1);
             }
+        },
+        None => None,
+    };
+    let groups = match &config.groups {
+        Some(path) => Some(read_groups(open(path).await).await?),
+        None => None,
+    };
+    // for the manifest
+    let mut inputs = Vec::new();
+    let (ingress, egress) = mpsc::unbounded_channel();
+    let h: JoinHandle<Result<()>> = match &config.listen {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            inputs.push(addr.clone());
+            tokio::spawn(serve(listener, config.clone(), ingress))
         }
-        Ok(())
-    });
-
-    let balances = Balances::new();
-    let transaction_history = TransactionHistory::new();
-
-    let g = tokio::spawn(async move {
-        loop {
-            match egress.recv().await {
-                Some(cmd) => {
-                    if let Err(e) = do_cmd(&cmd, &transaction_history, &balances).await {
-                        eprintln!("\"{:?}\" : {}", cmd, e);
-                    }
-                }
-                None => break,
+        None if !config.merge_inputs.is_empty() => {
+            let mut readers = Vec::new();
+            for path in expand(&config.input) {
+                readers.push(open(&path).await);
+                inputs.push(path.display().to_string());
             }
+            for path in &config.merge_inputs {
+                readers.push(open(path).await);
+                inputs.push(path.clone());
+            }
+            let config = config.clone();
+            tokio::spawn(async move { read_merged(readers, &config, ingress).await })
         }
-        println!("client,available,held, total, locked");
-        for balance in balances.read().await.iter() {
-            println!("{},{}", balance.0, balance.1);
+        None => {
+            let paths = expand(&config.input);
+            // opened up front, so that a bad path fails before any processing
+            let mut readers = Vec::new();
+            for path in paths {
+                readers.push(open(&path).await);
+                inputs.push(path.display().to_string());
+            }
+            let config = config.clone();
+            tokio::spawn(async move {
+                for reader in readers {
+                    read_input(reader, &config, ingress.clone()).await?;
+                }
+                Ok(())
+            })
         }
-    });
-
-    h.await.unwrap()?;
-    g.await.unwrap();
-
-    Ok(())
-}
-
-////////////////////////////////////////////////////////////////////////////////////////
-
-#[cfg(test)]
-mod tests {
-    use crate::{
-        do_cmd, Balances, BalancesType, ClientIdType, Result, TransactionHistory,
-        TransactionHistoryType,
     };
-    use crate::{Command, ErrorKind};
-    use csv_async::Trim;
-    use rust_decimal::Decimal;
-    use tokio_stream::StreamExt;
 
-    async fn consume(th: &TransactionHistoryType, bs: &BalancesType, data: &str) -> Result<()> {
-        let mut rdr = csv_async::AsyncReaderBuilder::new()
-            .flexible(true)
-            .trim(Trim::All)
-            .create_deserializer(data.as_bytes());
+    let balances = Balances::new();
+    let transaction_history = TransactionHistory::new();
 
-        let mut records = rdr.deserialize::<Command>();
-        while let Some(input) = records.next().await {
-            match input {
-                Ok(cmd) => {
-                    if let Err(e) = do_cmd(&cmd, th, bs).await {
-                        return Err(e);
+    if let (Some(_), Some(interval)) = (&config.listen, config.snapshot_interval) {
+        let balances = balances.clone();
+        let sort_by = config.sort_by;
+        let style = config.amount_style();
+        let label = config.currency_label.clone();
+        let bool_format = config.bool_format;
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(Duration::from_secs(interval));
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let snapshot = async {
+                    let mut records = account_records(&balances).await?;
+                    sort_by.sort(&mut records);
+                    for record in &mut records {
+                        record.label_currency(label.as_deref());
+                        record.restyle(style);
                     }
-                }
-                Err(e) => {
+                    let currency_column = records.iter().any(|r| r.currency.is_some());
+                    let stdout = std::io::stdout();
+                    let mut sink = CsvSink::new(stdout)
+                        .with_currency_column(currency_column)
+                        .with_bool_format(bool_format);
+                    output::write_accounts(&mut sink, &records).await
+                };
+                if let Err(e) = snapshot.await {
                     eprintln!("{}", e);
-                    return Ok(());
                 }
             }
-        }
-        Ok(())
+        });
     }
 
-    #[tokio::test]
-    async fn basic_deserialization_test() -> Result<()> {
-        let balances = Balances::new();
-        let txh = TransactionHistory::new();
-
-        consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        deposit, 1, 1, 1000
-        withdrawal, 1, 2, 500
-        deposit, 1, 3, 500
-        dispute, 1, 3
-        resolve, 1, 3",
-        )
-        .await?;
-
-        Ok(())
+    // opened up front, so that a bad path fails before any processing
+    let mut out = MultiWriter::open(&config.outputs)?;
+    let output_hash = HashWriter::default();
+    if config.manifest.is_some() {
+        out.push("manifest", Box::new(output_hash.clone()));
     }
-    #[tokio::test]
-    async fn basic_account_test() -> Result<()> {
-        let balances = Balances::new();
-        let txh = TransactionHistory::new();
-
-        consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        deposit, 1, 1, 1000
-        deposit, 1, 2, 500
-        withdrawal, 1, 3, 1500
-        deposit, 2, 4, 1000
-        withdrawal, 2, 5 , 1 ",
-        )
-        .await?;
-
-        let b = balances.read().await;
-        let b1 = b.get(&(1 as ClientIdType));
-        assert!(b1.is_some());
-        let b1 = b1.unwrap();
-        assert_eq!(b1.avail, Decimal::ZERO);
-        assert_eq!(b1.held, Decimal::ZERO);
-        assert!(!b1.locked);
-
-        let b2 = b.get(&(2 as ClientIdType));
-        assert!(b2.is_some());
-        let b2 = b2.unwrap();
-        assert_eq!(b2.avail, Decimal::new(999, 0));
-        assert_eq!(b1.held, Decimal::ZERO);
-        assert!(!b1.locked);
-
-        assert!((3..5).map(|x| b.get(&x)).all(|x| x.is_none()));
-
-        assert_eq!(txh.read().await.iter().count(), 5);
-
-        let e = consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        withdrawal, 1, 30, -1000",
-        )
-        .await
-        .unwrap_err();
-
-        assert!(match e.0 {
-            ErrorKind::AmountNotPositive => true,
-            _ => false,
-        });
+    let (updates, summary) = if config.incremental {
+        (Some(out), None)
+    } else {
+        (None, Some(out))
+    };
 
-        let e = consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        withdrawal, 1, 31, 0",
-        )
-        .await
-        .unwrap_err();
-        assert!(match e.0 {
-            ErrorKind::AmountNotPositive => true,
-            _ => false,
-        });
+    let g: JoinHandle<(RunMetrics, Result<()>)> = {
+        let config = config.clone();
+        let balances = balances.clone();
+        let transaction_history = transaction_history.clone();
+        tokio::spawn(async move {
+            let (th, bs) = (&transaction_history, &balances);
+            let mut metrics = RunMetrics::default();
+            let m = &mut metrics;
+            let outcome = match (updates, config.format) {
+                (None, _) => {
+                    process_commands(egress, &config, th, bs, m, None::<CsvSink<Outputs>>).await
+                }
+                (Some(out), OutputFormat::Csv) => {
+                    let sink = CsvSink::new(out).with_bool_format(config.bool_format);
+                    process_commands(egress, &config, th, bs, m, Some(sink)).await
+                }
+                (Some(out), OutputFormat::Json) => {
+                    process_commands(egress, &config, th, bs, m, Some(JsonSink::new(out))).await
+                }
+                (Some(out), OutputFormat::Table) => {
+                    process_commands(egress, &config, th, bs, m, Some(TableSink::new(out))).await
+                }
+            };
+            metrics.report(&config);
+            (metrics, outcome)
+        })
+    };
 
+    // the consumer's own error explains why the reader was cut off,
+    // so it takes precedence over the reader's
+    let (metrics, processed) = match g.await {
+        Ok(processed) => processed,
+        Err(_) => return Err(ErrorKind::ConsumerTerminated.into()),
+    };
+    let timed_out = matches!(&processed, Err(e) if matches!(e.kind(), ErrorKind::RunTimedOut(_)));
+    // a reader stuck on its input would never return
+    let read = if timed_out {
+        h.abort();
         Ok(())
+    } else {
+        h.await.unwrap()
+    };
+    write_summary(
+        &config,
+        &balances,
+        &transaction_history,
+        &metrics,
+        groups.as_ref(),
+        summary,
+    )
+    .await?;
+    if let Some(path) = &config.summary_json {
+        let summary = metrics.summary(&balances).await;
+        let json = serde_json::to_vec(&summary).map_err(std::io::Error::from)?;
+        tokio::fs::write(path, json).await?;
     }
-
-    #[tokio::test]
-    async fn check_decimal_precision() -> Result<()> {
-        let balances = Balances::new();
-        let txh = TransactionHistory::new();
-
-        consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        deposit, 1, 1, 1000.0001
-        deposit, 1, 2, 499.9999",
-        )
-        .await?;
-
-        let b = balances.read().await;
-        let b1 = b.get(&(1 as ClientIdType));
-        assert!(b1.is_some());
-        let b1 = b1.unwrap();
-        assert_eq!(b1.avail, Decimal::new(15000000, 4));
-
-        assert!(consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        deposit, 1, 3, 1000.00000
-        deposit, 1, 4, 499.99999"
-        )
-        .await
-        .is_err());
-        Ok(())
+    if let Some(path) = &config.manifest {
+        let manifest = RunManifest {
+            inputs,
+            summary: metrics.summary(&balances).await,
+            applied: metrics.applied,
+            output_hash: output_hash.digest(),
+        };
+        let json = serde_json::to_vec(&manifest).map_err(std::io::Error::from)?;
+        tokio::fs::write(path, json).await?;
     }
-
-    #[tokio::test]
-    async fn transaction_consistency() -> Result<()> {
-        let balances = Balances::new();
-        let txh = TransactionHistory::new();
-
-        consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        deposit, 1, 1, 1000
-        deposit, 1, 2, 500
-        withdrawal, 1, 3, 500
-        deposit, 2, 4, 1000
-        withdrawal, 2, 5 , 1 ",
-        )
-        .await?;
-
-        let e = consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        deposit, 1, 1, 1000",
-        )
-        .await
-        .unwrap_err();
-        assert!(match e.0 {
-            ErrorKind::TransactionAlreadyExist => true,
-            _ => false,
-        });
-
-        let e = consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        withdrawal, 1, 1, ",
-        )
-        .await
-        .unwrap_err();
-        assert!(match e.0 {
-            ErrorKind::TransactionAlreadyExist => true,
-            _ => false,
-        });
-
-        assert!(consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        dispute, 1, 2",
-        )
-        .await
-        .is_ok());
-
-        assert!(consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        resolve, 1, 2"
-        )
-        .await
-        .is_ok());
-
-        let e = consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        dispute, 1, 10",
-        )
-        .await
-        .unwrap_err();
-        assert!(match e.0 {
-            ErrorKind::ReferenceTransactionNotFound => true,
-            _ => false,
-        });
-
-        let e = consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        resolve, 1, 10",
-        )
-        .await
-        .unwrap_err();
-
-        assert!(match e.0 {
-            ErrorKind::ReferenceTransactionNotFound => true,
-            _ => false,
-        });
-
-        assert!(consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        dispute, 1, 2",
-        )
-        .await
-        .is_ok());
-
-        assert!(consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        chargeback, 1, 2"
-        )
-        .await
-        .is_ok());
-
-        Ok(())
+    for locked in locked_held(&balances, &transaction_history).await? {
+        eprintln!("warning: {}", locked);
     }
-
-    #[tokio::test]
-    async fn balance_locking_test() -> Result<()> {
-        let balances = Balances::new();
-        let txh = TransactionHistory::new();
-
-        let e = consume(
-            &txh,
-            &balances,
-            "\
-        type ,  client, tx, amount
-        deposit, 1, 1, 1000
-        dispute, 1, 1
-        chargeback, 1, 1
-        withdrawal, 1, 2 , 1",
-        )
-        .await
-        .unwrap_err();
-
-        assert!(match e.0 {
-            ErrorKind::LockedBalance => true,
-            _ => false,
-        });
-
-        Ok(())
+    if let Some(cmd) = &explained {
+        for line in explain(cmd, &config, &transaction_history, &balances).await {
+            eprintln!("{}", line);
+        }
+    }
+    if let Some(tx_id) = config.dispute_status {
+        let status = match transaction_history.read().await.is_in_dispute(&tx_id) {
+            Some(true) => "in dispute",
+            Some(false) => "not in dispute",
+            None => "not in the history",
+        };
+        eprintln!("transaction {}: {}", tx_id, status);
+    }
+    if config.verify {
+        verify_held_consistency(&balances, &transaction_history).await?;
+    }
+    if let Some(path) = &config.expect {
+        let records = account_records(&balances).await?;
+        let mismatches = reconcile(open(path).await, &records).await?;
+        for mismatch in &mismatches {
+            eprintln!("{}", mismatch);
+        }
+        if !mismatches.is_empty() {
+            return Err(ErrorKind::ExpectationMismatch(mismatches.len()).into());
+        }
+    }
+    if let Err(e) = &processed {
+        let code = match e.kind() {
+            ErrorKind::RunTimedOut(_) => TIMEOUT_EXIT_CODE,
+            ErrorKind::ClientLimitExceeded => CLIENT_LIMIT_EXIT_CODE,
+            _ => return processed,
+        };
+        eprintln!("error: {}", e);
+        exit(code);
     }
+    read
 }
@@ -0,0 +1,1092 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use rust_decimal::Decimal;
+use serde_derive::Serialize;
+
+use crate::{Balance, ClientIdType, Currency, ErrorKind, Result, TransactionIdType};
+
+/// Group of the clients missing from the `--groups` file.
+pub const UNGROUPED: &str = "ungrouped";
+
+/// Client column of the `--totals` footer, which is never a client id.
+pub const TOTAL_LABEL: &str = "TOTAL";
+
+/// A single line of the account summary.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountRecord {
+    pub client: ClientIdType,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Currency,
+    /// commands applied to the client in this run, with `--with-counts`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transactions: Option<usize>,
+    /// the group of the client, with `--groups`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+impl AccountRecord {
+    pub fn new(client: ClientIdType, currency: Option<&str>, balance: &Balance) -> Result<Self> {
+        Ok(Self {
+            client,
+            available: balance.avail,
+            held: balance.held,
+            total: balance.total()?,
+            locked: balance.locked,
+            currency: currency.map(str::to_string),
+            transactions: None,
+            group: None,
+        })
+    }
+
+    /// Formats the amounts for display, each of them on its own, so a
+    /// rounded total is not necessarily the sum of the rounded parts.
+    pub fn restyle(&mut self, style: AmountStyle) {
+        self.available = style.apply(self.available);
+        self.held = style.apply_held(self.held);
+        self.total = style.apply(self.total);
+    }
+
+    /// Sets the currency of an account in the default currency to `label`,
+    /// so that every record has one.
+    pub fn label_currency(&mut self, label: Option<&str>) {
+        if self.currency.is_none() {
+            self.currency = label.map(str::to_string);
+        }
+    }
+}
+
+/// How the amounts are written, set by `--display-scale`, `--trim-zeros`
+/// and `--negative-held`. The balances themselves are not affected.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AmountStyle {
+    /// decimal places to round to, unrounded when `None`
+    pub scale: Option<u32>,
+    /// drop trailing zeros, `1000.5000` is written as `1000.5`
+    pub trim_zeros: bool,
+    /// write `held` as a negative adjustment of the available funds
+    pub negative_held: bool,
+}
+
+impl AmountStyle {
+    pub fn apply(&self, amount: Decimal) -> Decimal {
+        let amount = match self.scale {
+            Some(scale) => amount.round_dp(scale),
+            None => amount,
+        };
+        if self.trim_zeros {
+            amount.normalize()
+        } else {
+            amount
+        }
+    }
+
+    /// Like `apply`, with the sign of `negative_held`. Zero is never negated.
+    pub fn apply_held(&self, held: Decimal) -> Decimal {
+        let held = self.apply(held);
+        if self.negative_held && !held.is_zero() {
+            -held
+        } else {
+            held
+        }
+    }
+}
+
+/// Destination of the account summary. Implement it to capture the
+/// records programmatically instead of rendering them as text.
+#[allow(async_fn_in_trait)]
+pub trait OutputSink {
+    async fn write_account(&mut self, record: &AccountRecord) -> Result<()>;
+    async fn finish(&mut self) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(ErrorKind::InvalidArgument.into()),
+        }
+    }
+}
+
+/// Rendering of the `locked` column of the CSV output.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum BoolFormat {
+    /// `true` and `false`
+    #[default]
+    Text,
+    /// `1` and `0`
+    Int,
+}
+
+impl FromStr for BoolFormat {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(BoolFormat::Text),
+            "int" => Ok(BoolFormat::Int),
+            _ => Err(ErrorKind::InvalidArgument.into()),
+        }
+    }
+}
+
+/// Column the account summary is ordered by.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SortKey {
+    #[default]
+    Client,
+    Total,
+    Available,
+    Held,
+}
+
+/// Order of the account summary, parsed from `<key>[:asc|:desc]`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SortBy {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+impl FromStr for SortBy {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, direction) = s.split_once(':').unwrap_or((s, "asc"));
+        let key = match key {
+            "client" => SortKey::Client,
+            "total" => SortKey::Total,
+            "available" => SortKey::Available,
+            "held" => SortKey::Held,
+            _ => bail!(ErrorKind::InvalidArgument),
+        };
+        let descending = match direction {
+            "asc" => false,
+            "desc" => true,
+            _ => bail!(ErrorKind::InvalidArgument),
+        };
+        Ok(SortBy { key, descending })
+    }
+}
+
+impl SortBy {
+    /// Orders the records in place. Records with equal keys are ordered by
+    /// client ascending, whatever the direction, and the accounts of one
+    /// client keep their relative order.
+    pub fn sort(&self, records: &mut [AccountRecord]) {
+        records.sort_by(|a, b| {
+            let ordering = match self.key {
+                SortKey::Client => a.client.cmp(&b.client),
+                SortKey::Total => a.total.cmp(&b.total),
+                SortKey::Available => a.available.cmp(&b.available),
+                SortKey::Held => a.held.cmp(&b.held),
+            };
+            let ordering = if self.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+            ordering.then(a.client.cmp(&b.client))
+        });
+    }
+}
+
+/// Appends `d` to `buf` exactly as its `Display` would, trailing zeros
+/// included, without going through the formatting machinery.
+pub fn write_decimal(buf: &mut Vec<u8>, d: Decimal) {
+    // the mantissa has at most 29 digits and the scale is at most 28
+    let mut digits = [b'0'; 30];
+    let mut mantissa = d.mantissa().unsigned_abs();
+    let mut len = 0;
+    while mantissa > 0 {
+        digits[len] = b'0' + (mantissa % 10) as u8;
+        mantissa /= 10;
+        len += 1;
+    }
+    let scale = d.scale() as usize;
+    // at least one digit before the point, zeros are already in place
+    let len = len.max(scale + 1);
+
+    if d.is_sign_negative() {
+        buf.push(b'-');
+    }
+    buf.extend(digits[scale..len].iter().rev());
+    if scale > 0 {
+        buf.push(b'.');
+        buf.extend(digits[..scale].iter().rev());
+    }
+}
+
+pub struct CsvSink<W: Write> {
+    out: W,
+    /// the row being written, kept to reuse its allocation
+    row: Vec<u8>,
+    header_written: bool,
+    currency_column: bool,
+    counts_column: bool,
+    group_column: bool,
+    bool_format: BoolFormat,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            row: Vec::new(),
+            header_written: false,
+            currency_column: false,
+            counts_column: false,
+            group_column: false,
+            bool_format: BoolFormat::default(),
+        }
+    }
+
+    /// Adds a `currency` column. Without it, the column is only added when
+    /// the first record has a currency.
+    pub fn with_currency_column(mut self, currency_column: bool) -> Self {
+        self.currency_column = currency_column;
+        self
+    }
+
+    /// Adds a `transactions` column, also added when the first record has a count.
+    pub fn with_counts_column(mut self, counts_column: bool) -> Self {
+        self.counts_column = counts_column;
+        self
+    }
+
+    /// Renders the `locked` column as `1`/`0` rather than `true`/`false`.
+    pub fn with_bool_format(mut self, bool_format: BoolFormat) -> Self {
+        self.bool_format = bool_format;
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Writes a `TOTAL` row for every currency after the accounts, with
+    /// the number of locked accounts in the `locked` column.
+    pub fn write_footer(&mut self, totals: &[Totals]) -> Result<()> {
+        self.write_header()?;
+        for t in totals {
+            write!(self.out, "{}", TOTAL_LABEL)?;
+            for amount in [t.available, t.held, t.total] {
+                write!(self.out, ",{}", amount)?;
+            }
+            write!(self.out, ",{}", t.locked)?;
+            if self.currency_column {
+                write!(self.out, ",{}", t.currency.as_deref().unwrap_or_default())?;
+            }
+            if self.counts_column {
+                write!(self.out, ",")?;
+            }
+            if self.group_column {
+                write!(self.out, ",")?;
+            }
+            writeln!(self.out)?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        if !self.header_written {
+            write!(self.out, "client,available,held, total, locked")?;
+            if self.currency_column {
+                write!(self.out, ", currency")?;
+            }
+            if self.counts_column {
+                write!(self.out, ", transactions")?;
+            }
+            if self.group_column {
+                write!(self.out, ", group")?;
+            }
+            writeln!(self.out)?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> OutputSink for CsvSink<W> {
+    async fn write_account(&mut self, record: &AccountRecord) -> Result<()> {
+        if !self.header_written {
+            self.currency_column |= record.currency.is_some();
+            self.counts_column |= record.transactions.is_some();
+            self.group_column |= record.group.is_some();
+        }
+        self.write_header()?;
+        // assembled in one buffer and written at once, the amounts being
+        // the bulk of the output are appended without `Display`
+        let row = &mut self.row;
+        row.clear();
+        write!(row, "{}", record.client)?;
+        for amount in [record.available, record.held, record.total] {
+            row.push(b',');
+            write_decimal(row, amount);
+        }
+        match self.bool_format {
+            BoolFormat::Text => write!(row, ",{}", record.locked)?,
+            BoolFormat::Int => write!(row, ",{}", u8::from(record.locked))?,
+        }
+        if self.currency_column {
+            row.push(b',');
+            row.extend_from_slice(record.currency.as_deref().unwrap_or_default().as_bytes());
+        }
+        if self.counts_column {
+            write!(row, ",{}", record.transactions.unwrap_or_default())?;
+        }
+        if self.group_column {
+            row.push(b',');
+            row.extend_from_slice(record.group.as_deref().unwrap_or_default().as_bytes());
+        }
+        row.push(b'\n');
+        self.out.write_all(row)?;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        self.write_header()?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Renders the summary as a JSON array, amounts are kept as exact decimal strings.
+pub struct JsonSink<W: Write> {
+    out: W,
+    count: usize,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, count: 0 }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> OutputSink for JsonSink<W> {
+    async fn write_account(&mut self, record: &AccountRecord) -> Result<()> {
+        self.out
+            .write_all(if self.count == 0 { b"[" } else { b"," })?;
+        serde_json::to_writer(&mut self.out, record).map_err(std::io::Error::from)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        if self.count == 0 {
+            self.out.write_all(b"[")?;
+        }
+        self.out.write_all(b"]\n")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Renders an aligned, human readable table. Rows are buffered until
+/// `finish` since the column widths depend on all of them.
+pub struct TableSink<W: Write> {
+    out: W,
+    rows: Vec<[String; 7]>,
+}
+
+impl<W: Write> TableSink<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+const TABLE_HEADER: [&str; 7] = [
+    "client",
+    "available",
+    "held",
+    "total",
+    "locked",
+    "currency",
+    "transactions",
+];
+
+impl<W: Write> OutputSink for TableSink<W> {
+    async fn write_account(&mut self, record: &AccountRecord) -> Result<()> {
+        self.rows.push([
+            record.client.to_string(),
+            record.available.to_string(),
+            record.held.to_string(),
+            record.total.to_string(),
+            record.locked.to_string(),
+            record.currency.clone().unwrap_or_default(),
+            record
+                .transactions
+                .map(|n| n.to_string())
+                .unwrap_or_default(),
+        ]);
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        // the optional columns are left out unless some account has a value
+        let columns: Vec<_> = (0..TABLE_HEADER.len())
+            .filter(|&i| i < 5 || self.rows.iter().any(|row| !row[i].is_empty()))
+            .collect();
+        let mut widths = TABLE_HEADER.map(str::len);
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        let header = TABLE_HEADER.map(String::from);
+        for row in std::iter::once(&header).chain(&self.rows) {
+            let line = columns
+                .iter()
+                .map(|&i| format!("{:>width$}", row[i], width = widths[i]))
+                .collect::<Vec<_>>()
+                .join("  ");
+            writeln!(self.out, "{}", line)?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Fans the output out to several writers. A writer which fails is reported
+/// once and skipped from then on, so the others still receive everything;
+/// only when every writer has failed is the error returned, and every
+/// later write fails as well.
+pub struct MultiWriter<W: Write> {
+    writers: Vec<(String, W, bool)>,
+}
+
+impl<W: Write> Default for MultiWriter<W> {
+    fn default() -> Self {
+        Self {
+            writers: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> MultiWriter<W> {
+    /// Adds a writer, `name` identifies it in error messages.
+    pub fn push(&mut self, name: impl Into<String>, writer: W) {
+        self.writers.push((name.into(), writer, false));
+    }
+
+    fn each(&mut self, mut op: impl FnMut(&mut W) -> std::io::Result<()>) -> std::io::Result<()> {
+        let mut last_error = None;
+        for (name, writer, failed) in self.writers.iter_mut().filter(|(_, _, failed)| !*failed) {
+            if let Err(e) = op(writer) {
+                eprintln!("{}: {}", name, e);
+                *failed = true;
+                last_error = Some(e);
+            }
+        }
+        let all_failed =
+            !self.writers.is_empty() && self.writers.iter().all(|(_, _, failed)| *failed);
+        match last_error {
+            Some(e) if all_failed => Err(e),
+            // every writer failed on an earlier call, nothing is written
+            None if all_failed => Err(std::io::ErrorKind::BrokenPipe.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl MultiWriter<Box<dyn Write + Send>> {
+    /// Opens the given files, `-` stands for the standard output, which is
+    /// also used when `paths` is empty.
+    pub fn open(paths: &[String]) -> Result<Self> {
+        let mut writer = Self::default();
+        if paths.is_empty() {
+            writer.push("stdout", Box::new(std::io::stdout()));
+        }
+        for path in paths {
+            if path == "-" {
+                writer.push("stdout", Box::new(std::io::stdout()));
+            } else {
+                writer.push(path.as_str(), Box::new(std::fs::File::create(path)?));
+            }
+        }
+        Ok(writer)
+    }
+}
+
+/// A writer keeping the 64 bit FNV-1a hash of everything written to it,
+/// e.g. next to the real writers of a `MultiWriter` to fingerprint the
+/// output. Clones share the hash.
+#[derive(Debug, Clone)]
+pub struct HashWriter(Arc<Mutex<u64>>);
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+impl Default for HashWriter {
+    fn default() -> Self {
+        HashWriter(Arc::new(Mutex::new(FNV_OFFSET_BASIS)))
+    }
+}
+
+impl HashWriter {
+    /// The hash of the bytes written so far, as 16 hexadecimal digits.
+    pub fn digest(&self) -> String {
+        format!("{:016x}", *self.0.lock().unwrap())
+    }
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut hash = self.0.lock().unwrap();
+        for &byte in buf {
+            *hash = (*hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for MultiWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.each(|writer| writer.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.each(|writer| writer.flush())
+    }
+}
+
+impl<S: OutputSink> OutputSink for &mut S {
+    async fn write_account(&mut self, record: &AccountRecord) -> Result<()> {
+        (**self).write_account(record).await
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        (**self).finish().await
+    }
+}
+
+/// Feeds every record to the sink and finishes it.
+pub async fn write_accounts<S: OutputSink>(sink: &mut S, records: &[AccountRecord]) -> Result<()> {
+    for record in records {
+        sink.write_account(record).await?;
+    }
+    sink.finish().await
+}
+
+/// Sums of the account summary for one currency, and one group with `--groups`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Totals {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub accounts: usize,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// number of locked accounts
+    pub locked: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Currency,
+}
+
+impl Totals {
+    /// Formats the sums for display, like `AccountRecord::restyle`.
+    pub fn restyle(&mut self, style: AmountStyle) {
+        self.available = style.apply(self.available);
+        self.held = style.apply_held(self.held);
+        self.total = style.apply(self.total);
+    }
+}
+
+/// Adds up the records of every group and currency, ordered by group and
+/// currency. The sums of
+/// many accounts can exceed what `Decimal` holds even though every single
+/// balance fits, which is reported as `SummaryOverflow`.
+pub fn totals(records: &[AccountRecord]) -> Result<Vec<Totals>> {
+    sum_by(records, |record| record.group.as_deref())
+}
+
+/// Adds up the records of every currency, whatever their group.
+pub fn grand_totals(records: &[AccountRecord]) -> Result<Vec<Totals>> {
+    sum_by(records, |_| None)
+}
+
+fn sum_by<'a>(
+    records: &'a [AccountRecord],
+    group_of: impl Fn(&'a AccountRecord) -> Option<&'a str>,
+) -> Result<Vec<Totals>> {
+    fn add(a: Decimal, b: Decimal) -> Result<Decimal> {
+        a.checked_add(b)
+            .ok_or_else(|| ErrorKind::SummaryOverflow.into())
+    }
+
+    let mut totals: BTreeMap<(Option<&str>, &Currency), Totals> = BTreeMap::new();
+    for record in records {
+        let group = group_of(record);
+        let sums = totals
+            .entry((group, &record.currency))
+            .or_insert_with(|| Totals {
+                group: group.map(str::to_string),
+                currency: record.currency.clone(),
+                ..Totals::default()
+            });
+        sums.accounts += 1;
+        sums.available = add(sums.available, record.available)?;
+        sums.held = add(sums.held, record.held)?;
+        sums.total = add(sums.total, record.total)?;
+        sums.locked += usize::from(record.locked);
+    }
+    Ok(totals.into_values().collect())
+}
+
+/// Writes the totals as CSV, or as a JSON array with `OutputFormat::Json`.
+pub fn write_totals<W: Write>(mut out: W, totals: &[Totals], format: OutputFormat) -> Result<()> {
+    if format == OutputFormat::Json {
+        serde_json::to_writer(&mut out, totals).map_err(std::io::Error::from)?;
+        writeln!(out)?;
+    } else {
+        let group_column = totals.iter().any(|t| t.group.is_some());
+        let currency_column = totals.iter().any(|t| t.currency.is_some());
+        if group_column {
+            write!(out, "group,")?;
+        }
+        write!(out, "accounts,available,held,total,locked")?;
+        if currency_column {
+            write!(out, ",currency")?;
+        }
+        writeln!(out)?;
+        for t in totals {
+            if group_column {
+                write!(out, "{},", t.group.as_deref().unwrap_or_default())?;
+            }
+            write!(
+                out,
+                "{},{},{},{},{}",
+                t.accounts, t.available, t.held, t.total, t.locked
+            )?;
+            if currency_column {
+                write!(out, ",{}", t.currency.as_deref().unwrap_or_default())?;
+            }
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// A transaction of the history, for `--dump-history`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryRecord {
+    pub tx: TransactionIdType,
+    pub client: ClientIdType,
+    #[serde(rename = "type")]
+    pub type_: &'static str,
+    pub amount: Decimal,
+    pub in_dispute: bool,
+    /// the part of `amount` held by the open dispute
+    pub held: Decimal,
+    /// charged back, it can no longer be disputed
+    pub finalized: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Currency,
+}
+
+/// Writes the history as CSV, or as a JSON array with `OutputFormat::Json`.
+pub fn write_history<W: Write>(
+    mut out: W,
+    records: &[HistoryRecord],
+    format: OutputFormat,
+) -> Result<()> {
+    if format == OutputFormat::Json {
+        serde_json::to_writer(&mut out, records).map_err(std::io::Error::from)?;
+        writeln!(out)?;
+    } else {
+        let currency_column = records.iter().any(|r| r.currency.is_some());
+        write!(out, "tx,client,type,amount,in_dispute,held,finalized")?;
+        if currency_column {
+            write!(out, ",currency")?;
+        }
+        writeln!(out)?;
+        for r in records {
+            write!(
+                out,
+                "{},{},{},{},{},{},{}",
+                r.tx, r.client, r.type_, r.amount, r.in_dispute, r.held, r.finalized
+            )?;
+            if currency_column {
+                write!(out, ",{}", r.currency.as_deref().unwrap_or_default())?;
+            }
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<AccountRecord> {
+        vec![
+            AccountRecord {
+                client: 1,
+                available: Decimal::new(15, 1),
+                held: Decimal::ZERO,
+                total: Decimal::new(15, 1),
+                locked: false,
+                currency: None,
+                transactions: None,
+                group: None,
+            },
+            AccountRecord {
+                client: 20,
+                available: Decimal::new(100000, 0),
+                held: Decimal::new(5, 0),
+                total: Decimal::new(100005, 0),
+                locked: true,
+                currency: None,
+                transactions: None,
+                group: None,
+            },
+        ]
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("broken"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn multi_writer_test() -> Result<()> {
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        {
+            let mut writer: MultiWriter<Box<dyn Write>> = MultiWriter::default();
+            writer.push("first", Box::new(&mut first));
+            writer.push("broken", Box::new(Broken));
+            writer.push("second", Box::new(&mut second));
+            write_accounts(&mut CsvSink::new(writer), &records()).await?;
+        }
+        assert!(!first.is_empty());
+        assert_eq!(first, second);
+
+        let mut writer = MultiWriter::default();
+        writer.push("broken", Broken);
+        let mut sink = CsvSink::new(writer);
+        assert!(write_accounts(&mut sink, &records()).await.is_err());
+        // nor are the writes after the failure reported as written
+        let mut writer = sink.into_inner();
+        assert_eq!(
+            writer.write(b"1,1,0,1,false\n").unwrap_err().kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+        assert!(writer.flush().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sort_by_test() -> Result<()> {
+        let record = |client, total: i64| AccountRecord {
+            client,
+            available: Decimal::new(total, 0),
+            held: Decimal::ZERO,
+            total: Decimal::new(total, 0),
+            locked: false,
+            currency: None,
+            transactions: None,
+            group: None,
+        };
+        let mut records = vec![record(1, 10), record(2, 300), record(3, 20), record(4, 300)];
+
+        "total:desc".parse::<SortBy>()?.sort(&mut records);
+        let clients: Vec<_> = records.iter().map(|r| r.client).collect();
+        assert_eq!(clients, vec![2, 4, 3, 1]);
+
+        SortBy::default().sort(&mut records);
+        let clients: Vec<_> = records.iter().map(|r| r.client).collect();
+        assert_eq!(clients, vec![1, 2, 3, 4]);
+
+        // ties are broken by client, not by the order the records came in
+        let mut records = vec![
+            record(4, 300),
+            record(1, 10),
+            record(5, 20),
+            record(2, 300),
+            record(3, 20),
+        ];
+        "total:desc".parse::<SortBy>()?.sort(&mut records);
+        let clients: Vec<_> = records.iter().map(|r| r.client).collect();
+        assert_eq!(clients, vec![2, 4, 3, 5, 1]);
+        "total".parse::<SortBy>()?.sort(&mut records);
+        let clients: Vec<_> = records.iter().map(|r| r.client).collect();
+        assert_eq!(clients, vec![1, 3, 5, 2, 4]);
+
+        assert!("total:up".parse::<SortBy>().is_err());
+        assert!("locked".parse::<SortBy>().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn renderers_test() -> Result<()> {
+        let mut csv = CsvSink::new(Vec::new());
+        write_accounts(&mut csv, &records()).await?;
+        assert_eq!(
+            String::from_utf8(csv.out).unwrap(),
+            "client,available,held, total, locked\n1,1.5,0,1.5,false\n20,100000,5,100005,true\n"
+        );
+
+        let mut json = JsonSink::new(Vec::new());
+        write_accounts(&mut json, &records()).await?;
+        assert_eq!(
+            String::from_utf8(json.out).unwrap(),
+            "[{\"client\":1,\"available\":\"1.5\",\"held\":\"0\",\"total\":\"1.5\",\"locked\":false},\
+             {\"client\":20,\"available\":\"100000\",\"held\":\"5\",\"total\":\"100005\",\"locked\":true}]\n"
+        );
+
+        let mut table = TableSink::new(Vec::new());
+        write_accounts(&mut table, &records()).await?;
+        assert_eq!(
+            String::from_utf8(table.out).unwrap(),
+            "\
+client  available  held   total  locked
+     1        1.5     0     1.5   false
+    20     100000     5  100005    true
+"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bool_format_test() -> Result<()> {
+        let mut csv = CsvSink::new(Vec::new()).with_bool_format("int".parse()?);
+        write_accounts(&mut csv, &records()).await?;
+        assert_eq!(
+            String::from_utf8(csv.out).unwrap(),
+            "client,available,held, total, locked\n1,1.5,0,1.5,0\n20,100000,5,100005,1\n"
+        );
+        assert_eq!("text".parse::<BoolFormat>()?, BoolFormat::default());
+        assert!("yes".parse::<BoolFormat>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn totals_test() -> Result<()> {
+        let mut records = records();
+        let mut eur = records[1].clone();
+        eur.currency = Some("EUR".to_string());
+        records.push(eur);
+
+        let sums = totals(&records)?;
+        assert_eq!(
+            sums,
+            vec![
+                Totals {
+                    group: None,
+                    accounts: 2,
+                    available: Decimal::new(1000015, 1),
+                    held: Decimal::new(5, 0),
+                    total: Decimal::new(1000065, 1),
+                    locked: 1,
+                    currency: None,
+                },
+                Totals {
+                    group: None,
+                    accounts: 1,
+                    available: Decimal::new(100000, 0),
+                    held: Decimal::new(5, 0),
+                    total: Decimal::new(100005, 0),
+                    locked: 1,
+                    currency: Some("EUR".to_string()),
+                },
+            ]
+        );
+        let mut csv = Vec::new();
+        write_totals(&mut csv, &sums, OutputFormat::Csv)?;
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "accounts,available,held,total,locked,currency\n\
+             2,100001.5,5,100006.5,1,\n\
+             1,100000,5,100005,1,EUR\n"
+        );
+
+        // every account fits, the sum of them does not
+        let large = AccountRecord {
+            available: Decimal::MAX / Decimal::new(4, 0),
+            total: Decimal::MAX / Decimal::new(4, 0),
+            ..records[0].clone()
+        };
+        let sums = totals(&vec![large.clone(); 3])?;
+        assert_eq!(sums[0].total, large.total * Decimal::new(3, 0));
+        let e = totals(&vec![large; 5]).unwrap_err();
+        assert!(matches!(e.0, ErrorKind::SummaryOverflow));
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_test() {
+        let mut record = AccountRecord {
+            available: Decimal::new(12345, 4),
+            held: Decimal::new(5, 3),
+            total: Decimal::new(12395, 4),
+            ..records()[0].clone()
+        };
+        record.restyle(AmountStyle {
+            scale: Some(2),
+            ..AmountStyle::default()
+        });
+        assert_eq!(record.available, Decimal::new(123, 2));
+        // half to even, like `--clamp-precision`
+        assert_eq!(record.held, Decimal::ZERO);
+        assert_eq!(record.total, Decimal::new(124, 2));
+    }
+
+    #[test]
+    fn negative_held_test() -> Result<()> {
+        for (negative_held, held) in [(false, Decimal::new(5, 0)), (true, Decimal::new(-5, 0))] {
+            let style = AmountStyle {
+                negative_held,
+                ..AmountStyle::default()
+            };
+            let mut record = records()[1].clone();
+            record.restyle(style);
+            assert_eq!(record.held, held);
+            assert_eq!(record.available, Decimal::new(100000, 0));
+            assert_eq!(record.total, Decimal::new(100005, 0));
+
+            let mut totals = grand_totals(&records())?;
+            totals[0].restyle(style);
+            assert_eq!(totals[0].held, held);
+            assert_eq!(totals[0].total, Decimal::new(1000065, 1));
+        }
+        // no `-0` for accounts without held funds
+        let mut record = records()[0].clone();
+        record.restyle(AmountStyle {
+            negative_held: true,
+            ..AmountStyle::default()
+        });
+        assert_eq!(record.held.to_string(), "0");
+
+        Ok(())
+    }
+
+    #[test]
+    fn hash_writer_test() -> Result<()> {
+        let hash = HashWriter::default();
+        assert_eq!(hash.digest(), "cbf29ce484222325");
+
+        // written through a clone, in two parts
+        let mut writer = hash.clone();
+        writer.write_all(b"fo")?;
+        writer.write_all(b"obar")?;
+        assert_eq!(hash.digest(), "85944171f73967e8");
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_decimal_test() {
+        let mut values = vec![
+            Decimal::ZERO,
+            -Decimal::ZERO,
+            Decimal::new(5, 1),
+            Decimal::new(-5, 1),
+            Decimal::new(20, 1),
+            Decimal::new(1, 28),
+            Decimal::new(-1, 28),
+            Decimal::new(100000, 0),
+            Decimal::new(12345678, 4),
+            Decimal::MAX,
+            Decimal::MIN,
+            Decimal::from_i128_with_scale(i128::from(u64::MAX) << 32, 28),
+        ];
+        // a spread of mantissas and scales
+        let mut x: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..1000 {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let mantissa = (x as i64 >> (x % 64)) as i128 * (x % 7) as i128;
+            values.push(Decimal::from_i128_with_scale(mantissa, (x % 29) as u32));
+        }
+
+        let mut buf = Vec::new();
+        for d in values {
+            buf.clear();
+            write_decimal(&mut buf, d);
+            assert_eq!(String::from_utf8(buf.clone()).unwrap(), d.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn trim_zeros_test() -> Result<()> {
+        let style = AmountStyle {
+            trim_zeros: true,
+            ..AmountStyle::default()
+        };
+        let mut record = AccountRecord {
+            available: Decimal::new(10005000, 4),
+            held: Decimal::new(0, 4),
+            total: Decimal::new(10005000, 4),
+            ..records()[0].clone()
+        };
+        record.restyle(style);
+
+        let mut csv = CsvSink::new(Vec::new());
+        write_accounts(&mut csv, &[record]).await?;
+        assert_eq!(
+            String::from_utf8(csv.out).unwrap(),
+            "client,available,held, total, locked\n1,1000.5,0,1000.5,false\n"
+        );
+
+        let style = AmountStyle {
+            scale: Some(2),
+            ..style
+        };
+        assert_eq!(style.apply(Decimal::new(12996, 4)).to_string(), "1.3");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,663 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Writes `contents` to a fresh temporary file named after the test.
+fn input_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ithaca-{}-{}.csv", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust-coding-test"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> &str {
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+#[test]
+fn normal_run() {
+    let input = input_file(
+        "normal",
+        "\
+type, client, tx, amount
+deposit, 2, 1, 1.5
+deposit, 1, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+withdrawal, 2, 5, 3.0
+dispute, 1, 3
+",
+    );
+
+    let output = run(&[input.to_str().unwrap()]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "\
+client,available,held, total, locked
+1,0.5,2.0,2.5,false
+2,1.5,0,1.5,false
+"
+    );
+}
+
+#[test]
+fn empty_file() {
+    let input = input_file("empty", "");
+
+    let output = run(&[input.to_str().unwrap()]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "client,available,held, total, locked\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "warning: the input is empty\n"
+    );
+}
+
+#[test]
+fn header_only() {
+    let input = input_file("header-only", "type, client, tx, amount\n");
+    let rejected = input_file(
+        "all-rejected",
+        "type, client, tx, amount\nwithdrawal, 1, 1, 5\n",
+    );
+
+    let output = run(&[input.to_str().unwrap()]);
+    let all_rejected = run(&["--quiet", rejected.to_str().unwrap()]);
+    for path in [&input, &rejected] {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "warning: the input has a header but no rows\n"
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&all_rejected.stderr),
+        "rejected 1 commands\n"
+    );
+}
+
+#[test]
+fn merged_header_only() {
+    let input = input_file(
+        "merged",
+        "type, client, tx, amount, timestamp\ndeposit, 1, 1, 5, 1\n",
+    );
+    let empty = input_file(
+        "merged-header-only",
+        "type, client, tx, amount, timestamp\n",
+    );
+
+    let output = run(&[input.to_str().unwrap(), "--merge", empty.to_str().unwrap()]);
+    for path in [&input, &empty] {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "client,available,held, total, locked\n1,5,0,5,false\n"
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "warning: the input has a header but no rows\n"
+    );
+}
+
+#[test]
+fn missing_file() {
+    let input = std::env::temp_dir().join("ithaca-does-not-exist.csv");
+
+    let output = run(&[input.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(66));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        format!(
+            "error: cannot open '{}': No such file or directory\n",
+            input.display()
+        )
+    );
+}
+
+#[test]
+fn client_limit() {
+    let input = input_file(
+        "client-limit",
+        "type, client, tx, amount\ndeposit, 1, 1, 5\ndeposit, 2, 2, 5\ndeposit, 3, 3, 5\n",
+    );
+
+    let output = run(&["--quiet", "--max-clients", "2", input.to_str().unwrap()]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert_eq!(output.status.code(), Some(65));
+    // the accounts processed before the limit are still written
+    assert_eq!(
+        stdout(&output),
+        "client,available,held, total, locked\n1,5,0,5,false\n2,5,0,5,false\n"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .ends_with("error: more distinct clients than the client limit\n"));
+}
+
+#[test]
+fn invalid_explain_row() {
+    let input = input_file("invalid-explain", "type, client, tx, amount\n");
+
+    let output = run(&["--explain", "bogus, 1, 2", input.to_str().unwrap()]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(!output.status.success());
+    assert_eq!(stdout(&output), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("error: invalid --explain row: "));
+    assert!(stderr.contains("Usage: "));
+    assert!(!stderr.contains("backtrace"));
+}
+
+#[test]
+fn missing_argument() {
+    let output = run(&[]);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("Usage:"));
+}
+
+#[test]
+fn currency_symbol_without_lenient_amounts() {
+    let output = run(&["--currency-symbol", "$", "input.csv"]);
+
+    assert!(!output.status.success());
+    assert_eq!(stdout(&output), "");
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("Usage:"));
+}
+
+#[test]
+fn fail_fast_on_lock() {
+    let input = input_file(
+        "fail-fast-on-lock",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10
+dispute, 1, 1
+chargeback, 1, 1
+deposit, 1, 2, 5
+withdrawal, 1, 3, 5
+",
+    );
+
+    let output = run(&["--fail-fast-on-lock", input.to_str().unwrap()]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "skipped 2 commands of locked accounts\n"
+    );
+    assert_eq!(
+        stdout(&output),
+        "client,available,held, total, locked\n1,0,0,0,true\n"
+    );
+}
+
+#[test]
+fn expect() {
+    let input = input_file(
+        "expect-input",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10
+",
+    );
+    let matching = input_file(
+        "expect-matching",
+        "client,available,held,total,locked\n1,10,0,10,false\n",
+    );
+    let mismatching = input_file(
+        "expect-mismatching",
+        "client,available,held,total,locked\n1,12,0,12,false\n",
+    );
+
+    let ok = run(&[
+        "--expect",
+        matching.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    let failed = run(&[
+        "--expect",
+        mismatching.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    for path in [&input, &matching, &mismatching] {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    assert!(ok.status.success());
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8_lossy(&failed.stderr);
+    assert!(
+        stderr.starts_with(
+            "client 1: available expected 12, actual 10\nclient 1: total expected 12, actual 10\n"
+        ),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn locked_with_held_funds() {
+    let input = input_file(
+        "locked-with-held",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 4
+dispute, 1, 1
+dispute, 1, 2
+chargeback, 1, 1
+",
+    );
+
+    let output = run(&[input.to_str().unwrap()]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "warning: client 1 is locked with 4 held by the disputes of 2\n"
+    );
+    assert_eq!(
+        stdout(&output),
+        "client,available,held, total, locked\n1,0,4,4,true\n"
+    );
+}
+
+#[test]
+fn quiet() {
+    let input = input_file(
+        "quiet",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10
+withdrawal, 1, 2, 20
+deposit, 1, 3, abc
+deposit, x, 4, 1
+dispute, 1, 9
+",
+    );
+
+    let noisy = run(&[input.to_str().unwrap()]);
+    let quiet = run(&["--quiet", input.to_str().unwrap()]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(quiet.status.success());
+    assert_eq!(String::from_utf8_lossy(&noisy.stderr).lines().count(), 4);
+    // the row which cannot be parsed is not counted
+    assert_eq!(
+        String::from_utf8_lossy(&quiet.stderr),
+        "rejected 3 commands\n"
+    );
+    assert_eq!(stdout(&quiet), stdout(&noisy));
+}
+
+#[test]
+fn groups() {
+    let input = input_file(
+        "groups-input",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 20.5
+deposit, 3, 3, 30
+dispute, 3, 3
+deposit, 4, 4, 1
+",
+    );
+    let groups = input_file("groups", "client,group\n1,eu\n2,us\n3,eu\n");
+
+    let output = run(&[
+        "--groups",
+        groups.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    let summary_only = run(&[
+        "--summary-only",
+        "--groups",
+        groups.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    for path in [&input, &groups] {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "\
+client,available,held, total, locked, group
+1,10,0,10,false,eu
+2,20.5,0,20.5,false,us
+3,0,30,30,false,eu
+4,1,0,1,false,ungrouped
+
+group,accounts,available,held,total,locked
+eu,2,10,30,40,0
+ungrouped,1,1,0,1,0
+us,1,20.5,0,20.5,0
+"
+    );
+    assert_eq!(
+        stdout(&summary_only),
+        "\
+group,accounts,available,held,total,locked
+eu,2,10,30,40,0
+ungrouped,1,1,0,1,0
+us,1,20.5,0,20.5,0
+"
+    );
+}
+
+#[test]
+fn groups_json() {
+    let output = run(&["--groups", "groups.csv", "--format", "json", "input.csv"]);
+
+    assert!(!output.status.success());
+    assert_eq!(stdout(&output), "");
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("Usage:"));
+}
+
+#[test]
+fn totals() {
+    let input = input_file(
+        "totals",
+        "\
+type, client, tx, amount
+deposit, 2, 1, 1.5
+deposit, 1, 2, 2.25
+deposit, 3, 3, 10
+dispute, 3, 3
+chargeback, 3, 3
+deposit, 1, 4, 5
+dispute, 1, 4
+",
+    );
+
+    let output = run(&[
+        "--totals",
+        "--sort-by",
+        "total:desc",
+        input.to_str().unwrap(),
+    ]);
+    let json = run(&["--totals", "--format", "json", input.to_str().unwrap()]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "\
+client,available,held, total, locked
+1,2.25,5,7.25,false
+2,1.5,0,1.5,false
+3,0,0,0,true
+TOTAL,3.75,5,8.75,1
+"
+    );
+    assert!(!json.status.success());
+}
+
+#[test]
+fn currency_label() {
+    let input = input_file(
+        "currency-label",
+        "\
+type, client, tx, amount, currency
+deposit, 2, 1, 5
+deposit, 1, 2, 3
+deposit, 1, 3, 7, USD
+",
+    );
+
+    let output = run(&["--currency-label", "EUR", input.to_str().unwrap()]);
+    let json = run(&[
+        "--currency-label",
+        "EUR",
+        "--format",
+        "json",
+        input.to_str().unwrap(),
+    ]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(output.status.success());
+    // an account with a currency of its own keeps it
+    assert_eq!(
+        stdout(&output),
+        "\
+client,available,held, total, locked, currency
+1,3,0,3,false,EUR
+1,7,0,7,false,USD
+2,5,0,5,false,EUR
+"
+    );
+    assert!(stdout(&json).contains(
+        r#""client":2,"available":"5","held":"0","total":"5","locked":false,"currency":"EUR""#
+    ));
+}
+
+#[test]
+fn pretty() {
+    let input = input_file(
+        "pretty",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 1.5
+deposit, 200, 2, 123456.7891
+deposit, 200, 3, 4
+dispute, 200, 3
+",
+    );
+
+    let output = run(&["--pretty", input.to_str().unwrap()]);
+    std::fs::remove_file(&input).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "\
+client    available  held        total  locked
+     1          1.5     0          1.5   false
+   200  123456.7891     4  123460.7891   false
+"
+    );
+}
+
+#[test]
+fn summary_json() {
+    let input = input_file(
+        "summary-json-input",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 5
+withdrawal, 1, 3, 20
+dispute, 2, 2
+chargeback, 2, 2
+deposit, 2, 4, 1
+dispute, 1, 9
+deposit, x, 5, 1
+",
+    );
+    let summary = input_file("summary-json", "");
+
+    let output = run(&[
+        "--summary-json",
+        summary.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    let json = std::fs::read_to_string(&summary).unwrap();
+    for path in [&input, &summary] {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    assert!(output.status.success());
+    // the row which cannot be parsed never reaches the engine
+    assert_eq!(
+        json,
+        "{\"rows_read\":7,\"accepted\":4,\"rejected\":3,\
+         \"by_type\":{\"chargeback\":1,\"deposit\":3,\"dispute\":2,\"withdrawal\":1},\
+         \"by_error\":{\"FundsInsufficientForGivenOperation\":1,\"LockedBalance\":1,\
+         \"ReferenceTransactionNotFound\":1},\"clients\":2,\"locked\":1}"
+    );
+}
+
+#[test]
+fn manifest() {
+    let input = input_file(
+        "manifest-input",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 5
+withdrawal, 1, 3, 20
+dispute, 2, 2
+chargeback, 2, 2
+",
+    );
+    let manifest = input_file("manifest", "");
+
+    let output = run(&[
+        "--manifest",
+        manifest.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    let json = std::fs::read_to_string(&manifest).unwrap();
+    for path in [&input, &manifest] {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "client,available,held, total, locked\n1,10,0,10,false\n2,0,0,0,true\n"
+    );
+    // the hash is the FNV-1a of the output above
+    assert_eq!(
+        json,
+        format!(
+            "{{\"inputs\":[\"{}\"],\"rows_read\":5,\"accepted\":4,\"rejected\":1,\
+             \"by_type\":{{\"chargeback\":1,\"deposit\":2,\"dispute\":1,\"withdrawal\":1}},\
+             \"by_error\":{{\"FundsInsufficientForGivenOperation\":1}},\"clients\":2,\
+             \"locked\":1,\"applied\":4,\"output_hash\":\"96159d364cf4843e\"}}",
+            input.display()
+        )
+    );
+}
+
+#[test]
+fn dump_history() {
+    let input = input_file(
+        "dump-history-input",
+        "\
+type, client, tx, amount
+deposit, 1, 3, 10.50
+withdrawal, 1, 1, 2
+deposit, 2, 2, 5
+dispute, 2, 2
+deposit, 1, 4, 1
+dispute, 1, 4
+chargeback, 1, 4
+",
+    );
+    let history = input_file("dump-history", "");
+
+    let csv = run(&[
+        "--dump-history",
+        history.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    let csv_history = std::fs::read_to_string(&history).unwrap();
+    let json = run(&[
+        "--format",
+        "json",
+        "--dump-history",
+        history.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    let json_history = std::fs::read_to_string(&history).unwrap();
+    for path in [&input, &history] {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    assert!(csv.status.success());
+    assert!(json.status.success());
+    assert_eq!(
+        csv_history,
+        "\
+tx,client,type,amount,in_dispute,held,finalized
+1,1,withdrawal,2,false,0,false
+2,2,deposit,5,true,5,false
+3,1,deposit,10.50,false,0,false
+4,1,deposit,1,false,0,true
+"
+    );
+    assert!(json_history.starts_with(
+        "[{\"tx\":1,\"client\":1,\"type\":\"withdrawal\",\"amount\":\"2\",\
+         \"in_dispute\":false,\"held\":\"0\",\"finalized\":false},"
+    ));
+    assert_eq!(json_history.matches("\"tx\"").count(), 4);
+}
+
+#[test]
+fn input_pattern() {
+    let dir = std::env::temp_dir().join(format!("ithaca-{}-pattern", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let files = [
+        // only funded by the file before it in sorted order
+        (
+            "2024-02.csv",
+            "type, client, tx, amount\nwithdrawal, 1, 2, 7\n",
+        ),
+        (
+            "2024-01.csv",
+            "type, client, tx, amount\ndeposit, 1, 1, 10\n",
+        ),
+        (
+            "2024-03.csv",
+            "type, client, tx, amount\ndeposit, 2, 3, 1\n",
+        ),
+        (
+            "2023-12.csv",
+            "type, client, tx, amount\ndeposit, 3, 4, 1\n",
+        ),
+    ];
+    for (name, contents) in files {
+        std::fs::write(dir.join(name), contents).unwrap();
+    }
+
+    let output = run(&[dir.join("2024-*.csv").to_str().unwrap()]);
+    let pattern = dir.join("2025-*.csv");
+    let unmatched = run(&[pattern.to_str().unwrap()]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "client,available,held, total, locked\n1,3,0,3,false\n2,1,0,1,false\n"
+    );
+    assert_eq!(unmatched.status.code(), Some(66));
+    assert_eq!(
+        String::from_utf8_lossy(&unmatched.stderr),
+        format!("error: no input matches {}\n", pattern.display())
+    );
+}
@@ -0,0 +1,156 @@
+//! Deterministic fuzzing of the reader and the engine.
+//!
+//! Every file of `tests/corpus` is fed through the pipeline as is and then
+//! mutated a number of times with a seeded generator. Any failure has to be
+//! reported as an `Err`; a panic fails the test, and so does a run leaving
+//! held funds which do not match the open disputes. `FUZZ_SEED` and
+//! `FUZZ_ITERATIONS` override the defaults for longer exploratory runs.
+
+use std::path::Path;
+
+use rust_coding_test::config::Config;
+use rust_coding_test::output::{AccountRecord, OutputSink};
+use rust_coding_test::{
+    process_commands, read_commands, verify_held_consistency, Balances, Result, RunMetrics,
+    TransactionHistory,
+};
+
+const DEFAULT_SEED: u64 = 0x1ca7_0d15_5e05;
+const DEFAULT_ITERATIONS: usize = 200;
+
+/// Fragments worth splicing into inputs, mostly field boundaries and
+/// amounts around the limits of `Decimal`.
+const TOKENS: &[&[u8]] = &[
+    b",",
+    b"\n",
+    b"\"",
+    b"-",
+    b".",
+    b" ",
+    b"0",
+    b"79228162514264337593543950335",
+    b"0.0000000000000000000000000001",
+    b"65535",
+    b"4294967295",
+    b"deposit",
+    b"withdrawal",
+    b"dispute",
+    b"resolve",
+    b"chargeback",
+    b"open",
+];
+
+/// xorshift64, enough to make the mutations reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+}
+
+fn mutate(rng: &mut Rng, input: &[u8]) -> Vec<u8> {
+    let mut data = input.to_vec();
+    for _ in 0..=rng.below(8) {
+        let at = rng.below(data.len() + 1);
+        match rng.below(4) {
+            0 if !data.is_empty() => {
+                let at = at.min(data.len() - 1);
+                data[at] = rng.next() as u8;
+            }
+            1 if at < data.len() => {
+                data.remove(at);
+            }
+            2 => {
+                let token = TOKENS[rng.below(TOKENS.len())];
+                data.splice(at..at, token.iter().copied());
+            }
+            _ => {
+                // duplicate a line, e.g. to repeat a deposit or a chargeback
+                let start = data[..at]
+                    .iter()
+                    .rposition(|&b| b == b'\n')
+                    .map_or(0, |p| p + 1);
+                let end = data[at..]
+                    .iter()
+                    .position(|&b| b == b'\n')
+                    .map_or(data.len(), |p| at + p + 1);
+                let line = data[start..end].to_vec();
+                data.splice(end..end, line);
+            }
+        }
+    }
+    data
+}
+
+/// Collects the records instead of printing them.
+#[derive(Default)]
+struct Discard(usize);
+
+impl OutputSink for Discard {
+    async fn write_account(&mut self, _record: &AccountRecord) -> Result<()> {
+        self.0 += 1;
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+async fn run(input: &[u8]) {
+    let config = Config::default();
+    let balances = Balances::new();
+    let history = TransactionHistory::new();
+    let (ingress, egress) = tokio::sync::mpsc::unbounded_channel();
+
+    let read = read_commands(input, ingress).await;
+    let processed = process_commands(
+        egress,
+        &config,
+        &history,
+        &balances,
+        &mut RunMetrics::default(),
+        Some(Discard::default()),
+    )
+    .await;
+    // errors are fine, only panics are not
+    let _ = (read, processed);
+    verify_held_consistency(&balances, &history).await.unwrap();
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+#[tokio::test]
+async fn fuzz_corpus() {
+    let mut rng = Rng(env_or("FUZZ_SEED", DEFAULT_SEED).max(1));
+    let iterations = env_or("FUZZ_ITERATIONS", DEFAULT_ITERATIONS);
+
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut entries: Vec<_> = std::fs::read_dir(corpus)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    assert!(!entries.is_empty());
+
+    for path in entries {
+        let seed = std::fs::read(&path).unwrap();
+        run(&seed).await;
+        for _ in 0..iterations {
+            run(&mutate(&mut rng, &seed)).await;
+        }
+    }
+}
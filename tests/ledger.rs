@@ -0,0 +1,45 @@
+//! Builds `src/ledger.rs` on its own, as a module of this test rather than
+//! of the library. A use of anything around it, e.g. of tokio or of the
+//! engine's error types, fails to compile here.
+
+#[allow(dead_code)]
+#[path = "../src/ledger.rs"]
+mod ledger;
+
+use ledger::{check_amount, to_decimal, Balance, BalanceOperation, DecimalContext, LedgerError};
+use rust_decimal::{Decimal, RoundingStrategy};
+
+#[test]
+fn ledger_alone() -> Result<(), LedgerError> {
+    let amount = to_decimal("100.25", 4, None)?;
+    check_amount(amount)?;
+
+    let balance = Balance::new()
+        .deposit(amount)?
+        .dispute(Decimal::new(25, 2))?;
+    assert_eq!(balance.available(), Decimal::new(100, 0));
+    assert_eq!(balance.held(), Decimal::new(25, 2));
+
+    let balance = balance.chargeback(Decimal::new(25, 2))?;
+    assert!(balance.locked());
+    assert_eq!(balance.deposit(amount), Err(LedgerError::LockedBalance));
+
+    assert_eq!(
+        to_decimal("1.00005", 4, None),
+        Err(LedgerError::DecimalFormatError)
+    );
+    let context = DecimalContext {
+        precision: None,
+        rounding: RoundingStrategy::MidpointAwayFromZero,
+    };
+    assert_eq!(
+        to_decimal("1.00005", 4, Some(&context))?,
+        Decimal::new(10001, 4)
+    );
+    assert_eq!(
+        check_amount(Decimal::ZERO),
+        Err(LedgerError::AmountNotPositive)
+    );
+
+    Ok(())
+}